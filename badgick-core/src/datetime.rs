@@ -0,0 +1,157 @@
+const SECONDS_PER_DAY: u64 = 86_400;
+/// Days from 0000-03-01 to 1970-01-01
+const DAYS_TO_UNIX_EPOCH: i64 = 719_468;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InvalidDateTime;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Converts seconds since the Unix epoch, dates before it are not
+    /// supported.
+    pub const fn from_timestamp(timestamp: u64) -> Self {
+        let days = (timestamp / SECONDS_PER_DAY) as i64;
+        let seconds = timestamp % SECONDS_PER_DAY;
+
+        // Civil from days, with years starting in March so the leap day is last
+        let days = days + DAYS_TO_UNIX_EPOCH;
+        let era = days / 146_097;
+        let day_of_era = days - era * 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month + 2) / 5 + 1;
+        let month = if month < 10 { month + 3 } else { month - 9 };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (seconds / 3_600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+
+    pub const fn timestamp(&self) -> u64 {
+        // Days from civil, the inverse of the above
+        let year = self.year as i64 - if self.month <= 2 { 1 } else { 0 };
+        let month = self.month as i64;
+        let era = year / 400;
+        let year_of_era = year - era * 400;
+        let day_of_year =
+            (153 * if month > 2 { month - 3 } else { month + 9 } + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - DAYS_TO_UNIX_EPOCH;
+
+        days as u64 * SECONDS_PER_DAY
+            + self.hour as u64 * 3_600
+            + self.minute as u64 * 60
+            + self.second as u64
+    }
+
+    /// Whether all fields are in range, for dates from the Unix epoch on.
+    pub const fn is_valid(&self) -> bool {
+        self.year >= 1970
+            && self.month >= 1
+            && self.month <= 12
+            && self.day >= 1
+            && self.day <= days_in_month(self.year, self.month)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+    }
+
+    /// Day of the week, 0 is Monday.
+    pub const fn weekday(&self) -> u8 {
+        // 1970-01-01 was a Thursday
+        ((self.timestamp() / SECONDS_PER_DAY + 3) % 7) as u8
+    }
+}
+
+const fn days_in_month(year: u16, month: u8) -> u8 {
+    let leap = year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400));
+    match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const fn datetime(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> DateTime {
+        DateTime {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+        }
+    }
+
+    #[test]
+    fn epoch() {
+        let epoch = datetime(1970, 1, 1, 0, 0, 0);
+        assert_eq!(DateTime::from_timestamp(0), epoch);
+        assert_eq!(epoch.timestamp(), 0);
+    }
+
+    #[test]
+    fn leap_day() {
+        let leap_day = datetime(2000, 2, 29, 12, 34, 56);
+        assert_eq!(DateTime::from_timestamp(951_827_696), leap_day);
+        assert_eq!(leap_day.timestamp(), 951_827_696);
+        assert!(leap_day.is_valid());
+    }
+
+    #[test]
+    fn no_leap_day_in_2100() {
+        let before = datetime(2100, 2, 28, 23, 59, 59);
+        let after = datetime(2100, 3, 1, 0, 0, 0);
+        assert_eq!(DateTime::from_timestamp(4_107_542_399), before);
+        assert_eq!(DateTime::from_timestamp(4_107_542_400), after);
+        assert_eq!(after.timestamp(), 4_107_542_400);
+        assert!(!datetime(2100, 2, 29, 0, 0, 0).is_valid());
+    }
+
+    #[test]
+    fn round_trip() {
+        for timestamp in (0..5_000_000_000).step_by(86_399 * 37) {
+            assert_eq!(DateTime::from_timestamp(timestamp).timestamp(), timestamp);
+        }
+    }
+
+    #[test]
+    fn weekday() {
+        assert_eq!(datetime(1970, 1, 1, 0, 0, 0).weekday(), 3);
+        assert_eq!(datetime(2000, 2, 29, 23, 59, 59).weekday(), 1);
+        assert_eq!(datetime(2024, 1, 1, 0, 0, 0).weekday(), 0);
+        assert_eq!(datetime(2100, 2, 28, 0, 0, 0).weekday(), 6);
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(!datetime(2024, 0, 1, 0, 0, 0).is_valid());
+        assert!(!datetime(2024, 13, 1, 0, 0, 0).is_valid());
+        assert!(!datetime(2024, 1, 0, 0, 0, 0).is_valid());
+        assert!(!datetime(2024, 4, 31, 0, 0, 0).is_valid());
+        assert!(!datetime(2024, 1, 1, 24, 0, 0).is_valid());
+        assert!(!datetime(1969, 12, 31, 0, 0, 0).is_valid());
+    }
+}
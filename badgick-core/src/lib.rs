@@ -0,0 +1,8 @@
+//! Parts of the firmware without any hardware access, so they can be unit
+//! tested on the host.
+
+#![cfg_attr(not(test), no_std)]
+
+pub mod datetime;
+pub mod touch;
+pub mod ws2812;
@@ -0,0 +1,180 @@
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    Touched,
+    Released,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct FilterConfig {
+    /// Drop below the baseline counted as a touch
+    pub threshold: u16,
+    /// How much less than `threshold` releases again
+    pub hysteresis: u16,
+    /// Consecutive samples needed to change state
+    pub debounce: u8,
+    /// Baseline follows with a weight of 1 / 2^`drift_shift` per sample
+    pub drift_shift: u8,
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 100,
+            hysteresis: 20,
+            debounce: 3,
+            drift_shift: 6,
+        }
+    }
+}
+
+/// Turns the raw counts of one channel into touch events, without any
+/// hardware access.
+#[derive(Clone, Copy, Debug)]
+pub struct Filter {
+    config: FilterConfig,
+    /// 8 fractional bits, zero until the first sample
+    baseline: u32,
+    touched: bool,
+    pending: u8,
+}
+
+impl Filter {
+    pub const fn new(config: FilterConfig) -> Self {
+        Self {
+            config,
+            baseline: 0,
+            touched: false,
+            pending: 0,
+        }
+    }
+
+    pub const fn baseline(&self) -> u16 {
+        (self.baseline >> 8) as u16
+    }
+
+    pub const fn is_touched(&self) -> bool {
+        self.touched
+    }
+
+    pub fn update(&mut self, count: u16) -> Option<Event> {
+        if self.baseline == 0 {
+            self.baseline = (count as u32) << 8;
+            return None;
+        }
+
+        // A finger adds capacitance, so it charges to a lower count
+        let delta = self.baseline().saturating_sub(count);
+        let touched = if self.touched {
+            delta > self.config.threshold.saturating_sub(self.config.hysteresis)
+        } else {
+            delta > self.config.threshold
+        };
+
+        let mut event = None;
+        if touched != self.touched {
+            self.pending += 1;
+            if self.pending >= self.config.debounce {
+                self.touched = touched;
+                self.pending = 0;
+                event = Some(if touched {
+                    Event::Touched
+                } else {
+                    Event::Released
+                });
+            }
+        } else {
+            self.pending = 0;
+        }
+
+        // Only drifts while untouched, or a long touch would become the
+        // baseline
+        if !self.touched {
+            let target = (count as i32) << 8;
+            let baseline = self.baseline as i32;
+            self.baseline = (baseline + ((target - baseline) >> self.config.drift_shift)) as u32;
+        }
+
+        event
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds `trace` and returns the events with the index of their sample
+    fn run(filter: &mut Filter, trace: impl IntoIterator<Item = u16>) -> Vec<(usize, Event)> {
+        trace
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, count)| filter.update(count).map(|event| (i, event)))
+            .collect()
+    }
+
+    fn repeat(count: u16, n: usize) -> impl Iterator<Item = u16> {
+        core::iter::repeat_n(count, n)
+    }
+
+    #[test]
+    fn touch_and_release() {
+        let mut filter = Filter::new(FilterConfig::default());
+        let trace = repeat(1000, 10)
+            .chain(repeat(850, 10))
+            .chain(repeat(1000, 10));
+        assert_eq!(
+            run(&mut filter, trace),
+            [(12, Event::Touched), (22, Event::Released)]
+        );
+        assert!(!filter.is_touched());
+    }
+
+    #[test]
+    fn debounce() {
+        let mut filter = Filter::new(FilterConfig::default());
+        let trace = repeat(1000, 10)
+            .chain(repeat(850, 2))
+            .chain(repeat(1000, 5))
+            .chain(repeat(850, 2))
+            .chain(repeat(1000, 5));
+        assert_eq!(run(&mut filter, trace), []);
+    }
+
+    #[test]
+    fn hysteresis() {
+        let mut filter = Filter::new(FilterConfig::default());
+        // 90 below the baseline keeps it touched, 70 below releases
+        let trace = repeat(1000, 10)
+            .chain(repeat(850, 5))
+            .chain(repeat(910, 5))
+            .chain(repeat(930, 5));
+        assert_eq!(
+            run(&mut filter, trace),
+            [(12, Event::Touched), (22, Event::Released)]
+        );
+    }
+
+    #[test]
+    fn drift() {
+        let mut filter = Filter::new(FilterConfig::default());
+        // Slowly falling by 200, e.g. from humidity, is no touch
+        let trace = (0..800).map(|i| 1000 - i / 4);
+        assert_eq!(run(&mut filter, trace), []);
+        assert!(filter.baseline().abs_diff(800) < 20);
+
+        // But is the new reference for one
+        let trace = repeat(800, 100).chain(repeat(650, 5));
+        assert_eq!(run(&mut filter, trace), [(102, Event::Touched)]);
+    }
+
+    #[test]
+    fn no_drift_while_touched() {
+        let mut filter = Filter::new(FilterConfig::default());
+        let trace = repeat(1000, 10).chain(repeat(850, 1000));
+        assert_eq!(run(&mut filter, trace), [(12, Event::Touched)]);
+        // Only moved while the touch was being debounced
+        assert!(filter.baseline() > 990);
+
+        let trace = repeat(1000, 5);
+        assert_eq!(run(&mut filter, trace), [(2, Event::Released)]);
+    }
+}
@@ -0,0 +1,112 @@
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BufferTooSmall;
+
+/// Turns colors into PWM widths, without any hardware access.
+#[derive(Clone, Copy, Debug)]
+pub struct Encoder {
+    t0h: u32,
+    t1h: u32,
+}
+
+impl Encoder {
+    /// High times of 0.4 µs for a zero and 0.8 µs for a one, out of 1.25 µs.
+    pub const fn new(period: u32) -> Self {
+        Self {
+            t0h: period * 8 / 25,
+            t1h: period * 16 / 25,
+        }
+    }
+
+    /// Widths needed for `leds` LEDs.
+    pub const fn buffer_len(leds: usize) -> usize {
+        leds * 24 + 1
+    }
+
+    /// Writes one width per bit of the `[r, g, b]` colors in GRB order, most
+    /// significant first, and a trailing zero that keeps the line low
+    /// afterwards. Returns the number of widths written.
+    pub fn encode(
+        &self,
+        colors: impl IntoIterator<Item = [u8; 3]>,
+        buffer: &mut [u32],
+    ) -> Result<usize, BufferTooSmall> {
+        let mut len = 0;
+        for [r, g, b] in colors {
+            let bits = (g as u32) << 16 | (r as u32) << 8 | b as u32;
+            let widths = buffer.get_mut(len..len + 24).ok_or(BufferTooSmall)?;
+            for (i, width) in widths.iter_mut().enumerate() {
+                *width = if bits & 1 << (23 - i) != 0 {
+                    self.t1h
+                } else {
+                    self.t0h
+                };
+            }
+            len += 24;
+        }
+
+        *buffer.get_mut(len).ok_or(BufferTooSmall)? = 0;
+        Ok(len + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Period at 60 MHz
+    const PERIOD: u32 = 60_000_000 / 800_000;
+    const T0H: u32 = 24;
+    const T1H: u32 = 48;
+
+    #[test]
+    fn timing() {
+        let encoder = Encoder::new(PERIOD);
+        let mut buffer = [u32::MAX; Encoder::buffer_len(1)];
+        encoder.encode([[0x00, 0x00, 0x00]], &mut buffer).unwrap();
+        assert_eq!(buffer[0], T0H);
+        encoder.encode([[0xFF, 0xFF, 0xFF]], &mut buffer).unwrap();
+        assert_eq!(buffer[0], T1H);
+    }
+
+    #[test]
+    fn grb_order() {
+        let encoder = Encoder::new(PERIOD);
+        let mut buffer = [u32::MAX; Encoder::buffer_len(1)];
+        let len = encoder.encode([[0x12, 0x80, 0x01]], &mut buffer).unwrap();
+        assert_eq!(len, 25);
+
+        let bits = buffer[..24].iter().fold(0, |bits, &width| {
+            assert!(width == T0H || width == T1H);
+            bits << 1 | (width == T1H) as u32
+        });
+        assert_eq!(bits, 0x80_12_01);
+    }
+
+    #[test]
+    fn trailing_zero() {
+        let encoder = Encoder::new(PERIOD);
+        let mut buffer = [u32::MAX; Encoder::buffer_len(2) + 1];
+        let len = encoder
+            .encode([[0xFF, 0xFF, 0xFF], [0xFF, 0xFF, 0xFF]], &mut buffer)
+            .unwrap();
+        assert_eq!(len, 49);
+        assert_eq!(buffer[48], 0);
+        // Past the end is left alone
+        assert_eq!(buffer[49], u32::MAX);
+
+        let len = encoder.encode([], &mut buffer).unwrap();
+        assert_eq!(len, 1);
+        assert_eq!(buffer[0], 0);
+    }
+
+    #[test]
+    fn buffer_too_small() {
+        let encoder = Encoder::new(PERIOD);
+        let colors = [[0x01, 0x02, 0x03]; 2];
+        let mut buffer = [0; Encoder::buffer_len(2) - 1];
+        assert_eq!(encoder.encode(colors, &mut buffer), Err(BufferTooSmall));
+        let mut buffer = [0; Encoder::buffer_len(2) - 24];
+        assert_eq!(encoder.encode(colors, &mut buffer), Err(BufferTooSmall));
+        assert_eq!(encoder.encode(colors, &mut []), Err(BufferTooSmall));
+    }
+}
@@ -10,3 +10,13 @@ REGION_ALIAS("REGION_DATA", RAM);
 REGION_ALIAS("REGION_BSS", RAM);
 REGION_ALIAS("REGION_HEAP", RAM);
 REGION_ALIAS("REGION_STACK", RAM);
+
+/* Not zeroed or initialized at start-up, survives software and watchdog resets */
+SECTIONS
+{
+  .uninit (NOLOAD) : ALIGN(4)
+  {
+    *(.uninit .uninit.*);
+    . = ALIGN(4);
+  } > RAM
+} INSERT AFTER .bss;
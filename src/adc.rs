@@ -0,0 +1,494 @@
+use crate::{
+    gpio::{Analog, Pin},
+    pfic::PficExt,
+    power::StayAwake,
+    sys::Clocks,
+};
+use core::{
+    future::poll_fn,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+    task::Poll,
+};
+use embassy_sync::waitqueue::AtomicWaker;
+use embassy_time::Timer;
+use fugit::HertzU32;
+use pac::{
+    Pfic, Sys,
+    interrupt::{ExternalInterrupt, Priority},
+};
+
+/// Reference voltage in mV
+pub const VREF_MV: i32 = 1_050;
+
+static EOC_FIRED: AtomicBool = AtomicBool::new(false);
+static EOC_WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Times the sampling DMA wrapped around the buffer
+static DMA_LAPS: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Gain {
+    Minus12dB = 0,
+    Minus6dB = 1,
+    Zero = 2,
+    Plus6dB = 3,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClockDiv {
+    MHz3_2 = 0,
+    MHz2_67 = 1,
+    MHz5_33 = 2,
+    MHz4 = 3,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    pub gain: Gain,
+    pub clock: ClockDiv,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            gain: Gain::Zero,
+            clock: ClockDiv::MHz3_2,
+        }
+    }
+}
+
+/// Input of a conversion
+pub trait Channel {
+    const CHANNEL: u8;
+    /// Measured against the second pin of the pair instead of ground
+    const DIFFERENTIAL: bool = false;
+    /// Overrides the configured gain
+    const GAIN: Option<Gain> = None;
+}
+
+impl<const P: char, const N: u8> Channel for Pin<P, N, Analog> {
+    const CHANNEL: u8 = Self::ADC_CHANNEL;
+}
+
+/// AIN0 against AIN2
+impl Channel for (Pin<'A', 4, Analog>, Pin<'A', 12, Analog>) {
+    const CHANNEL: u8 = 0;
+    const DIFFERENTIAL: bool = true;
+}
+
+/// AIN1 against AIN3
+impl Channel for (Pin<'A', 5, Analog>, Pin<'A', 13, Analog>) {
+    const CHANNEL: u8 = 1;
+    const DIFFERENTIAL: bool = true;
+}
+
+/// Supply voltage
+pub struct Vbat;
+
+impl Channel for Vbat {
+    const CHANNEL: u8 = 14;
+    /// Only in range at the lowest gain
+    const GAIN: Option<Gain> = Some(Gain::Minus12dB);
+}
+
+/// Calibration word in the info flash, the reading at the temperature in the
+/// upper half (25 °C if zero) in the lower one
+const ROM_CFG_TMP_25C: *const u32 = 0x0007_F014 as *const u32;
+
+pub struct TemperatureSensor {
+    _private: (),
+}
+
+impl TemperatureSensor {
+    pub fn new(adc: &mut Adc) -> Self {
+        adc.adc.tem_sensor().write(|w| w.tem_sen_pwr_on().set_bit());
+
+        Self { _private: () }
+    }
+
+    /// Temperature in °C.
+    pub async fn read(&mut self, adc: &mut Adc) -> i32 {
+        let data = adc.read(self).await;
+        Self::to_celsius(data)
+    }
+
+    pub fn blocking_read(&mut self, adc: &mut Adc) -> i32 {
+        let data = adc.blocking_read(self);
+        Self::to_celsius(data)
+    }
+
+    pub fn to_celsius(data: u16) -> i32 {
+        let cal = unsafe { ROM_CFG_TMP_25C.read_volatile() };
+        let cal_temp = match cal >> 16 {
+            0 => 25,
+            cal_temp => cal_temp as i32,
+        };
+        // 2.7 counts per °C
+        cal_temp + (data as i32 - (cal & 0xFFFF) as i32) * 10 / 27
+    }
+}
+
+impl Channel for TemperatureSensor {
+    const CHANNEL: u8 = 15;
+    const DIFFERENTIAL: bool = true;
+    const GAIN: Option<Gain> = Some(Gain::Plus6dB);
+}
+
+impl Drop for TemperatureSensor {
+    fn drop(&mut self) {
+        let adc = unsafe { pac::Adc::steal() };
+        adc.tem_sensor().write(|w| w.tem_sen_pwr_on().clear_bit());
+    }
+}
+
+/// Zero offset for each gain, added to single-ended readings. It is measured
+/// single-ended, so differential ones are left as they are.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Calibration {
+    offsets: [i16; 4],
+}
+
+impl Calibration {
+    pub const fn offset(&self, gain: Gain) -> i16 {
+        self.offsets[gain as usize]
+    }
+
+    /// For keeping it in the data-flash across resets.
+    pub fn to_bytes(&self) -> [u8; 8] {
+        let mut bytes = [0; 8];
+        for (bytes, offset) in bytes.chunks_exact_mut(2).zip(self.offsets) {
+            bytes.copy_from_slice(&offset.to_le_bytes());
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: [u8; 8]) -> Self {
+        let mut offsets = [0; 4];
+        for (offset, bytes) in offsets.iter_mut().zip(bytes.chunks_exact(2)) {
+            *offset = i16::from_le_bytes([bytes[0], bytes[1]]);
+        }
+        Self { offsets }
+    }
+
+    fn apply(&self, gain: Gain, differential: bool, data: u16) -> u16 {
+        if differential {
+            return data;
+        }
+
+        (data as i32 + self.offset(gain) as i32).clamp(0, 0xFFF) as u16
+    }
+}
+
+#[derive(Debug)]
+pub struct Adc {
+    adc: pac::Adc,
+    config: Config,
+    calibration: Calibration,
+}
+
+impl Adc {
+    pub fn new(adc: pac::Adc, config: Config, pfic: &Pfic) -> Self {
+        pfic.set_priority(ExternalInterrupt::ADC, Priority::P15);
+        pfic.enable(ExternalInterrupt::ADC);
+
+        Self {
+            adc,
+            config,
+            calibration: Calibration::default(),
+        }
+    }
+
+    /// For the touch key, which shares the converter.
+    pub(crate) fn regs(&self) -> &pac::Adc {
+        &self.adc
+    }
+
+    pub fn set_gain(&mut self, gain: Gain) {
+        self.config.gain = gain;
+    }
+
+    /// Measures the zero offset of each gain with the inputs shorted, and
+    /// corrects all following single-ended readings by it.
+    pub fn calibrate(&mut self) -> Calibration {
+        for gain in [Gain::Minus12dB, Gain::Minus6dB, Gain::Zero, Gain::Plus6dB] {
+            self.adc.cfg().write(|w| unsafe {
+                w.power_on()
+                    .set_bit()
+                    .buf_en()
+                    .set_bit()
+                    .ofs_test()
+                    .set_bit()
+                    .pga_gain()
+                    .bits(gain as u8)
+                    .clk_div()
+                    .bits(self.config.clock as u8)
+            });
+
+            // The first conversion after powering on is off
+            let mut sum = 0;
+            for i in 0..17 {
+                self.adc.convert().write(|w| w.start().set_bit());
+                while self.adc.convert().read().start().bit_is_set() {}
+                if i != 0 {
+                    // Inverted in offset test mode
+                    sum += !self.adc.data().read().data().bits() as u32 & 0xFFF;
+                }
+            }
+            self.calibration.offsets[gain as usize] = 2048 - ((sum + 8) / 16) as i16;
+        }
+
+        self.adc.cfg().write(|w| w.power_on().clear_bit());
+        self.calibration
+    }
+
+    /// Restores a previous [`Adc::calibrate`].
+    pub fn set_calibration(&mut self, calibration: Calibration) {
+        self.calibration = calibration;
+    }
+
+    pub fn blocking_read<C: Channel>(&mut self, _channel: &mut C) -> u16 {
+        self.start::<C>(false);
+        while self.adc.convert().read().start().bit_is_set() {}
+        self.finish::<C>()
+    }
+
+    pub async fn read<C: Channel>(&mut self, _channel: &mut C) -> u16 {
+        let _awake = StayAwake::new();
+        EOC_FIRED.store(false, Ordering::SeqCst);
+        self.start::<C>(true);
+
+        poll_fn(|cx| {
+            EOC_WAKER.register(cx.waker());
+            if EOC_FIRED.swap(false, Ordering::SeqCst) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await;
+
+        self.finish::<C>()
+    }
+
+    /// Converts a raw value read with the current gain to mV.
+    pub fn to_millivolts<C: Channel>(&self, data: u16) -> i32 {
+        to_millivolts(data, C::GAIN.unwrap_or(self.config.gain), C::DIFFERENTIAL)
+    }
+
+    /// Samples `channel` at `rate` into `buffer` over and over, read by
+    /// halves. The rate has to be within Fsys/4096 and Fsys/16, 14.6 kHz to
+    /// 3.75 MHz at 60 MHz.
+    pub fn start_sampling<C: Channel>(
+        mut self,
+        _channel: &mut C,
+        buffer: &'static mut [u16],
+        rate: HertzU32,
+        clocks: &Clocks,
+    ) -> Result<Sampler, RateOutOfRange> {
+        assert!(buffer.len() >= 2 && buffer.len().is_multiple_of(2));
+        // Sampled every (256 - auto_cycle) * 16 Fsys cycles
+        let period = clocks.fsys().to_Hz() / rate.to_Hz() / 16;
+        if !(1..=256).contains(&period) {
+            return Err(RateOutOfRange { adc: self, buffer });
+        }
+
+        let start = buffer.as_mut_ptr() as u32;
+        DMA_LAPS.store(0, Ordering::SeqCst);
+
+        self.start::<C>(false);
+        self.adc
+            .auto_cycle()
+            .write(|w| unsafe { w.auto_cycle().bits((256 - period) as u8) });
+        // Two bytes per sample
+        set_dma_range(&self.adc, start, buffer.len() as u32 * 2);
+        self.adc.dma_if().write(|w| w.if_dma_end().set_bit());
+        self.adc.ctrl_dma().write(|w| {
+            w.dma_enable()
+                .set_bit()
+                .dma_loop()
+                .set_bit()
+                .ie_dma_end()
+                .set_bit()
+                .auto_en()
+                .set_bit()
+        });
+
+        Ok(Sampler {
+            _awake: StayAwake::new(),
+            gain: C::GAIN.unwrap_or(self.config.gain),
+            differential: C::DIFFERENTIAL,
+            sample_cycles: period * 16,
+            fsys: clocks.fsys().to_Hz(),
+            adc: self,
+            buffer,
+            read: 0,
+        })
+    }
+
+    fn start<C: Channel>(&mut self, interrupt: bool) {
+        // The touch key shares the converter
+        let sys = unsafe { Sys::steal() };
+        sys.tkey_cfg().modify(|_, w| w.tkey_pwr_on().clear_bit());
+
+        self.adc
+            .channel()
+            .write(|w| unsafe { w.ch_inx().bits(C::CHANNEL) });
+        self.adc.cfg().write(|w| unsafe {
+            w.power_on()
+                .set_bit()
+                .buf_en()
+                .bit(!C::DIFFERENTIAL)
+                .diff_en()
+                .bit(C::DIFFERENTIAL)
+                .pga_gain()
+                .bits(C::GAIN.unwrap_or(self.config.gain) as u8)
+                .clk_div()
+                .bits(self.config.clock as u8)
+        });
+        self.adc.ctrl_dma().modify(|_, w| w.ie_eoc().bit(interrupt));
+        self.adc.convert().write(|w| w.start().set_bit());
+    }
+
+    fn finish<C: Channel>(&mut self) -> u16 {
+        let data = self.adc.data().read().data().bits();
+        self.adc.cfg().modify(|_, w| w.power_on().clear_bit());
+        self.calibration
+            .apply(C::GAIN.unwrap_or(self.config.gain), C::DIFFERENTIAL, data)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Overrun;
+
+/// The sampling rate can't be reached at the current Fsys, gives back the
+/// ADC and buffer.
+#[derive(Debug)]
+pub struct RateOutOfRange {
+    pub adc: Adc,
+    pub buffer: &'static mut [u16],
+}
+
+/// Continuous sampling by DMA around the buffer, at a fixed rate. One half
+/// is read while the DMA writes the other one.
+pub struct Sampler {
+    /// The DMA keeps sampling between reads
+    _awake: StayAwake,
+    gain: Gain,
+    differential: bool,
+    /// Fsys cycles per sample
+    sample_cycles: u32,
+    fsys: u32,
+    adc: Adc,
+    buffer: &'static mut [u16],
+    /// Halves read so far
+    read: u64,
+}
+
+impl Sampler {
+    /// Waits for the next filled half and copies it into `out`, fails if the
+    /// DMA wrote into it before it was read completely.
+    pub async fn read(&mut self, out: &mut [u16]) -> Result<(), Overrun> {
+        let half_len = self.buffer.len() as u64 / 2;
+        assert!(out.len() as u64 == half_len);
+
+        let end = (self.read + 1) * half_len;
+        let written = loop {
+            let written = self.written();
+            if written >= end {
+                break written;
+            }
+
+            // The DMA has no interrupt halfway, so wait for the samples
+            // still missing
+            let micros = ((end - written) * self.sample_cycles as u64 * 1_000_000)
+                .div_ceil(self.fsys as u64);
+            Timer::after_micros(micros).await;
+        };
+
+        // Wrapped around into the half to read
+        if written > end + half_len {
+            self.read = written / half_len;
+            return Err(Overrun);
+        }
+
+        let offset = self.read as usize % 2 * half_len as usize;
+        let half = self.buffer[offset..].as_ptr();
+        for (i, out) in out.iter_mut().enumerate() {
+            // Written by the DMA behind the compiler's back
+            let data = unsafe { half.add(i).read_volatile() };
+            *out = self
+                .adc
+                .calibration
+                .apply(self.gain, self.differential, data);
+        }
+        self.read += 1;
+
+        // Wrapped around while copying
+        if self.written() > end + half_len {
+            return Err(Overrun);
+        }
+        Ok(())
+    }
+
+    /// Samples written since sampling started.
+    fn written(&self) -> u64 {
+        let len = self.buffer.len() as u64;
+        let start = self.buffer.as_ptr() as u32 as u16;
+        critical_section::with(|_| {
+            loop {
+                // Wrapped around, but the interrupt hasn't counted it yet
+                let wrapped = self.adc.adc.dma_if().read().if_dma_end().bit_is_set();
+                let now =
+                    unsafe { (self.adc.adc.dma_now().as_ptr() as *const u16).read_volatile() };
+                if self.adc.adc.dma_if().read().if_dma_end().bit_is_set() == wrapped {
+                    let laps = DMA_LAPS.load(Ordering::SeqCst) as u64 + wrapped as u64;
+                    let pos = (now.wrapping_sub(start) / 2) as u64 % len;
+                    return laps * len + pos;
+                }
+            }
+        })
+    }
+
+    pub fn stop(self) -> (Adc, &'static mut [u16]) {
+        self.adc.adc.ctrl_dma().write(|w| unsafe { w.bits(0) });
+        self.adc.adc.cfg().modify(|_, w| w.power_on().clear_bit());
+        (self.adc, self.buffer)
+    }
+}
+
+/// The DMA only takes and reports the lower 16 bits of RAM addresses, which
+/// the PAC declares as 8 bits wide.
+fn set_dma_range(adc: &pac::Adc, start: u32, len: u32) {
+    unsafe {
+        (adc.dma_beg().as_ptr() as *mut u16).write_volatile(start as u16);
+        (adc.dma_end().as_ptr() as *mut u16).write_volatile((start + len) as u16);
+    }
+}
+
+/// Single-ended inputs are centered on the reference, differential ones on
+/// zero.
+pub const fn to_millivolts(data: u16, gain: Gain, differential: bool) -> i32 {
+    // Full scale of 4096 is 2 Vref at 0 dB and scales with the gain
+    let mv = (data as i32 - 2048) * VREF_MV * 4 / (2048 << gain as u8);
+    if differential { mv } else { mv + VREF_MV }
+}
+
+#[riscv_rt::external_interrupt(ExternalInterrupt::ADC)]
+fn adc() {
+    let adc = unsafe { pac::Adc::steal() };
+    if adc.ctrl_dma().read().ie_eoc().bit_is_set() && adc.int_flag().read().if_eoc().bit_is_set() {
+        adc.ctrl_dma().modify(|_, w| w.ie_eoc().clear_bit());
+        // Writing the convert register clears the flag
+        adc.convert().write(|w| unsafe { w.bits(0) });
+
+        EOC_FIRED.store(true, Ordering::SeqCst);
+        EOC_WAKER.wake();
+    }
+
+    if adc.dma_if().read().if_dma_end().bit_is_set() {
+        adc.dma_if().write(|w| w.if_dma_end().set_bit());
+
+        // Carries on from the beginning by itself
+        DMA_LAPS.fetch_add(1, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,106 @@
+use crate::{
+    adc::{Adc, Vbat},
+    pfic::PficExt,
+    sys::with_safe_mode,
+};
+use core::{
+    future::poll_fn,
+    sync::atomic::{AtomicBool, Ordering},
+    task::Poll,
+};
+use embassy_sync::waitqueue::AtomicWaker;
+use pac::{
+    Pfic, Sys,
+    interrupt::{ExternalInterrupt, Priority},
+};
+
+static LOW_FIRED: AtomicBool = AtomicBool::new(false);
+static LOW_WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Voltage below which the battery reads as low, and 0.2 V further down as
+/// lower.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Threshold {
+    V1_9 = 0,
+    V2_1 = 1,
+    V2_3 = 2,
+    V2_5 = 3,
+}
+
+pub struct BatteryMonitor {
+    _private: (),
+}
+
+impl BatteryMonitor {
+    pub fn new(threshold: Threshold, pfic: &Pfic) -> Self {
+        let sys = unsafe { Sys::steal() };
+        with_safe_mode(|| {
+            sys.bat_det_cfg()
+                .write(|w| unsafe { w.bat_low_vth().bits(threshold as u8) });
+            sys.bat_det_ctrl().write(|w| w.bat_det_en().set_bit());
+        });
+
+        pfic.set_priority(ExternalInterrupt::WDOG_BAT, Priority::P15);
+        pfic.enable(ExternalInterrupt::WDOG_BAT);
+
+        Self { _private: () }
+    }
+
+    pub fn is_low(&self) -> bool {
+        let sys = unsafe { Sys::steal() };
+        sys.bat_status().read().bat_stat_low().bit_is_set()
+    }
+
+    pub fn is_lower(&self) -> bool {
+        let sys = unsafe { Sys::steal() };
+        sys.bat_status().read().bat_stat_lower().bit_is_set()
+    }
+
+    pub async fn wait_low(&mut self) {
+        let sys = unsafe { Sys::steal() };
+        LOW_FIRED.store(false, Ordering::SeqCst);
+        // The status is a level, so the interrupt is re-enabled for each wait
+        with_safe_mode(|| {
+            sys.bat_det_ctrl().modify(|_, w| w.bat_low_ie().set_bit());
+        });
+
+        poll_fn(|cx| {
+            LOW_WAKER.register(cx.waker());
+            if LOW_FIRED.swap(false, Ordering::SeqCst) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    /// Measures the battery voltage in mV with the ADC.
+    pub fn voltage_mv(&mut self, adc: &mut Adc) -> u32 {
+        let data = adc.blocking_read(&mut Vbat);
+        adc.to_millivolts::<Vbat>(data).max(0) as u32
+    }
+}
+
+impl Drop for BatteryMonitor {
+    fn drop(&mut self) {
+        let sys = unsafe { Sys::steal() };
+        with_safe_mode(|| {
+            sys.bat_det_ctrl().write(|w| unsafe { w.bits(0) });
+        });
+    }
+}
+
+/// Called from the shared WDOG_BAT interrupt.
+pub(crate) fn on_interrupt() {
+    let sys = unsafe { Sys::steal() };
+    let ctrl = sys.bat_det_ctrl().read();
+    if ctrl.bat_low_ie().bit_is_set() && sys.bat_status().read().bat_stat_low().bit_is_set() {
+        with_safe_mode(|| {
+            sys.bat_det_ctrl().modify(|_, w| w.bat_low_ie().clear_bit());
+        });
+
+        LOW_FIRED.store(true, Ordering::SeqCst);
+        LOW_WAKER.wake();
+    }
+}
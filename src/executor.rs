@@ -0,0 +1,51 @@
+use core::{
+    marker::PhantomData,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use embassy_executor::{Spawner, raw};
+
+#[cfg(feature = "time-driver-rtc")]
+use crate::rtcclk as time_driver;
+#[cfg(not(feature = "time-driver-rtc"))]
+use crate::sysclk as time_driver;
+
+static SIGNAL_WORK_THREAD_MODE: AtomicBool = AtomicBool::new(false);
+
+#[unsafe(export_name = "__pender")]
+fn __pender(_context: *mut ()) {
+    SIGNAL_WORK_THREAD_MODE.store(true, Ordering::SeqCst);
+}
+
+/// Thread mode executor, which lets the time driver decide how deep to sleep
+/// while there is no work.
+pub struct Executor {
+    inner: raw::Executor,
+    not_send: PhantomData<*mut ()>,
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        Self {
+            inner: raw::Executor::new(core::ptr::null_mut()),
+            not_send: PhantomData,
+        }
+    }
+
+    pub fn run(&'static mut self, init: impl FnOnce(Spawner)) -> ! {
+        init(self.inner.spawner());
+
+        loop {
+            unsafe { self.inner.poll() };
+
+            // Interrupts only ever set the signal, so checking and sleeping
+            // in the same critical section doesn't lose any wake-up
+            critical_section::with(|cs| {
+                if SIGNAL_WORK_THREAD_MODE.load(Ordering::SeqCst) {
+                    SIGNAL_WORK_THREAD_MODE.store(false, Ordering::SeqCst);
+                } else {
+                    time_driver::idle(cs);
+                }
+            });
+        }
+    }
+}
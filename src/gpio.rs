@@ -0,0 +1,447 @@
+use crate::{pfic::PficExt, sys::with_safe_mode};
+use core::{
+    convert::Infallible,
+    future::poll_fn,
+    marker::PhantomData,
+    sync::atomic::{AtomicU16, AtomicU32, Ordering},
+    task::Poll,
+};
+use embassy_sync::waitqueue::AtomicWaker;
+use embedded_hal_async::digital::Wait;
+use hal::digital::{ErrorType, InputPin, OutputPin, StatefulOutputPin};
+use pac::{
+    Pfic, Sys,
+    interrupt::{ExternalInterrupt, Priority},
+};
+
+/// Evaluates `$body` with `$regs` bound to the register block of `$port`,
+/// both ports have the same registers at different offsets
+macro_rules! port {
+    ($port:expr, |$regs:ident| $body:expr) => {
+        match $port {
+            'A' => {
+                let $regs = unsafe { &*pac::Gpioa::ptr() };
+                $body
+            }
+            'B' => {
+                let $regs = unsafe { &*pac::Gpiob::ptr() };
+                $body
+            }
+            _ => unreachable!(),
+        }
+    };
+}
+pub(crate) use port;
+
+static WAKERS: [[AtomicWaker; 16]; 2] = [const { [const { AtomicWaker::new() }; 16] }; 2];
+/// Pins with a wait in progress, by port
+static WAITING: [AtomicU32; 2] = [const { AtomicU32::new(0) }; 2];
+/// Port A pins in analog mode, all analog functions are on port A
+static ANALOG_PINS: AtomicU16 = AtomicU16::new(0);
+
+pub struct Input<PULL> {
+    _pull: PhantomData<PULL>,
+}
+
+pub struct Floating;
+pub struct PullUp;
+pub struct PullDown;
+
+/// Push-pull output
+pub struct Output;
+
+/// Digital input disabled, for the ADC and the 32 kHz crystal
+pub struct Analog;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Drive {
+    MA5,
+    MA20,
+}
+
+pub struct Pin<const P: char, const N: u8, MODE = Input<Floating>> {
+    _mode: PhantomData<MODE>,
+}
+
+impl<const P: char, const N: u8, MODE> Pin<P, N, MODE> {
+    const fn new() -> Self {
+        Self { _mode: PhantomData }
+    }
+
+    pub fn into_floating_input(self) -> Pin<P, N, Input<Floating>> {
+        set_analog(P, N, false);
+        set_mode(P, 1 << N, false, false, false);
+        Pin::new()
+    }
+
+    pub fn into_pull_up_input(self) -> Pin<P, N, Input<PullUp>> {
+        set_analog(P, N, false);
+        set_mode(P, 1 << N, false, true, false);
+        Pin::new()
+    }
+
+    pub fn into_pull_down_input(self) -> Pin<P, N, Input<PullDown>> {
+        set_analog(P, N, false);
+        set_mode(P, 1 << N, false, false, true);
+        Pin::new()
+    }
+
+    /// Starts out low with 5 mA drive.
+    pub fn into_push_pull_output(self) -> Pin<P, N, Output> {
+        set_analog(P, N, false);
+        port!(P, |regs| regs.clr().write(|w| unsafe { w.bits(1 << N) }));
+        set_mode(P, 1 << N, true, false, false);
+        Pin::new()
+    }
+
+    /// ADC channels 2/3, 4/5, 6/7 and 8/9 share the digital input disable, it
+    /// is only turned back on once both pins have left analog mode.
+    pub fn into_analog(self) -> Pin<P, N, Analog> {
+        const { assert!(analog_ie_bit(P, N).is_some(), "pin has no analog function") };
+        set_mode(P, 1 << N, false, false, false);
+        set_analog(P, N, true);
+        Pin::new()
+    }
+}
+
+impl<const P: char, const N: u8> Pin<P, N, Analog> {
+    pub const ADC_CHANNEL: u8 = match adc_channel(P, N) {
+        Some(channel) => channel,
+        None => panic!("pin has no ADC channel"),
+    };
+}
+
+impl<const P: char, const N: u8> Pin<P, N, Output> {
+    pub fn set_drive(&mut self, drive: Drive) {
+        set_mode(P, 1 << N, true, false, drive == Drive::MA20);
+    }
+}
+
+const fn adc_channel(port: char, n: u8) -> Option<u8> {
+    Some(match (port, n) {
+        ('A', 4) => 0,
+        ('A', 5) => 1,
+        ('A', 12) => 2,
+        ('A', 13) => 3,
+        ('A', 14) => 4,
+        ('A', 15) => 5,
+        ('A', 3) => 6,
+        ('A', 2) => 7,
+        ('A', 1) => 8,
+        ('A', 0) => 9,
+        ('A', 6) => 10,
+        ('A', 7) => 11,
+        ('A', 8) => 12,
+        ('A', 9) => 13,
+        _ => return None,
+    })
+}
+
+/// Bit in `pin_analog_ie`, channels 2/3, 4/5, 6/7 and 8/9 share one
+const fn analog_ie_bit(port: char, n: u8) -> Option<u8> {
+    Some(match adc_channel(port, n) {
+        Some(8 | 9) => 0,
+        Some(6 | 7) => 1,
+        Some(10) => 2,
+        Some(11) => 3,
+        Some(0) => 9,
+        Some(1) => 10,
+        Some(12) => 11,
+        Some(13) => 12,
+        Some(2 | 3) => 14,
+        Some(4 | 5) => 15,
+        // 32 kHz crystal
+        None if port == 'A' && (n == 10 || n == 11) => 13,
+        _ => return None,
+    })
+}
+
+fn set_analog(port: char, n: u8, analog: bool) {
+    let Some(bit) = analog_ie_bit(port, n) else {
+        return;
+    };
+
+    let sys = unsafe { Sys::steal() };
+    critical_section::with(|_| {
+        let mut pins = ANALOG_PINS.load(Ordering::Relaxed);
+        if analog {
+            pins |= 1 << n;
+        } else {
+            pins &= !(1 << n);
+        }
+        ANALOG_PINS.store(pins, Ordering::Relaxed);
+
+        // Kept disabled while the sibling pin is still analog
+        let disabled = (0..16).any(|i| pins & 1 << i != 0 && analog_ie_bit('A', i) == Some(bit));
+        sys.pin_analog_ie().modify(|r, w| unsafe {
+            w.bits(if disabled {
+                r.bits() | 1 << bit
+            } else {
+                r.bits() & !(1 << bit)
+            })
+        });
+    });
+}
+
+fn set_mode(port: char, mask: u32, output: bool, pu: bool, pd_drv: bool) {
+    let update = |bits: u32, set: bool| if set { bits | mask } else { bits & !mask };
+    critical_section::with(|_| {
+        port!(port, |regs| unsafe {
+            regs.pu().modify(|r, w| w.bits(update(r.bits(), pu)));
+            regs.pd_drv()
+                .modify(|r, w| w.bits(update(r.bits(), pd_drv)));
+            regs.dir().modify(|r, w| w.bits(update(r.bits(), output)));
+        });
+    });
+}
+
+impl<const P: char, const N: u8, MODE> ErrorType for Pin<P, N, MODE> {
+    type Error = Infallible;
+}
+
+impl<const P: char, const N: u8, PULL> InputPin for Pin<P, N, Input<PULL>> {
+    fn is_high(&mut self) -> Result<bool, Self::Error> {
+        Ok(port!(P, |regs| regs.pin().read().bits()) & 1 << N != 0)
+    }
+
+    fn is_low(&mut self) -> Result<bool, Self::Error> {
+        self.is_high().map(|high| !high)
+    }
+}
+
+impl<const P: char, const N: u8> OutputPin for Pin<P, N, Output> {
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        port!(P, |regs| regs.clr().write(|w| unsafe { w.bits(1 << N) }));
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        // There is no set register
+        critical_section::with(|_| {
+            port!(P, |regs| regs
+                .out()
+                .modify(|r, w| unsafe { w.bits(r.bits() | 1 << N) }));
+        });
+        Ok(())
+    }
+}
+
+impl<const P: char, const N: u8> StatefulOutputPin for Pin<P, N, Output> {
+    fn is_set_high(&mut self) -> Result<bool, Self::Error> {
+        Ok(port!(P, |regs| regs.out().read().bits()) & 1 << N != 0)
+    }
+
+    fn is_set_low(&mut self) -> Result<bool, Self::Error> {
+        self.is_set_high().map(|high| !high)
+    }
+}
+
+/// Enables the port interrupts, which are needed for [`Wait`], and lets them
+/// wake up from sleep.
+pub fn init(pfic: &Pfic) {
+    with_safe_mode(|| {
+        unsafe { Sys::steal() }
+            .slp_wake_ctrl()
+            .modify(|_, w| w.slp_gpio_wake().set_bit().wake_ev_mode().set_bit());
+    });
+
+    pfic.set_priority(ExternalInterrupt::GPIOA, Priority::P15);
+    pfic.enable(ExternalInterrupt::GPIOA);
+    pfic.set_priority(ExternalInterrupt::GPIOB, Priority::P15);
+    pfic.enable(ExternalInterrupt::GPIOB);
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Trigger {
+    Low,
+    High,
+    Falling,
+    Rising,
+}
+
+impl<const P: char, const N: u8, PULL> Pin<P, N, Input<PULL>> {
+    /// Bit in the interrupt registers, PB22/PB23 take over the ones of
+    /// PB8/PB9 and PB16..PB21 have none
+    const INT_BIT: u8 = match (P, N) {
+        ('B', 22 | 23) => N - 14,
+        ('B', 16..=21) => panic!("pin has no interrupt"),
+        _ => N,
+    };
+
+    /// Pins that can't wait at the same time, as one `pin_intx` bit moves
+    /// both interrupts
+    const SHARED: u32 = match (P, N) {
+        ('B', 8 | 9) => 0b11 << 22,
+        ('B', 22 | 23) => 0b11 << 8,
+        _ => 0,
+    };
+
+    async fn wait_for(&mut self, trigger: Trigger) {
+        let mask = 1 << Self::INT_BIT;
+        critical_section::with(|_| {
+            let waiting = &WAITING[(P == 'B') as usize];
+            assert!(
+                waiting.load(Ordering::Relaxed) & Self::SHARED == 0,
+                "PB8/PB9 and PB22/PB23 can't wait at the same time"
+            );
+            waiting.fetch_or(1 << N, Ordering::Relaxed);
+
+            if P == 'B' && N >= 22 {
+                let sys = unsafe { Sys::steal() };
+                sys.pin_alternate().modify(|_, w| w.pin_intx().set_bit());
+            }
+
+            port!(P, |regs| unsafe {
+                let edge = matches!(trigger, Trigger::Falling | Trigger::Rising);
+                regs.int_mode().modify(|r, w| {
+                    w.bits(if edge {
+                        r.bits() | mask
+                    } else {
+                        r.bits() & !mask
+                    })
+                });
+                // The output bit selects the polarity
+                if matches!(trigger, Trigger::High | Trigger::Rising) {
+                    regs.out().modify(|r, w| w.bits(r.bits() | 1 << N));
+                } else {
+                    regs.clr().write(|w| w.bits(1 << N));
+                }
+                regs.int_if().write(|w| w.bits(mask));
+                regs.int_en().modify(|r, w| w.bits(r.bits() | mask));
+            });
+        });
+
+        let _guard = WaitGuard::<P, N> { mask };
+
+        // Disabled again by the interrupt once it fired
+        poll_fn(|cx| {
+            WAKERS[(P == 'B') as usize][Self::INT_BIT as usize].register(cx.waker());
+            if port!(P, |regs| regs.int_en().read().bits()) & mask == 0 {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+}
+
+/// Disarms the interrupt once the wait is over, also when cancelled, and
+/// hands bits 8/9 back to PB8/PB9
+struct WaitGuard<const P: char, const N: u8> {
+    mask: u32,
+}
+
+impl<const P: char, const N: u8> Drop for WaitGuard<P, N> {
+    fn drop(&mut self) {
+        critical_section::with(|_| {
+            port!(P, |regs| regs
+                .int_en()
+                .modify(|r, w| unsafe { w.bits(r.bits() & !self.mask) }));
+
+            let waiting = &WAITING[(P == 'B') as usize];
+            let left = waiting.fetch_and(!(1 << N), Ordering::Relaxed) & !(1 << N);
+            if P == 'B' && N >= 22 && left & 0b11 << 22 == 0 {
+                let sys = unsafe { Sys::steal() };
+                sys.pin_alternate().modify(|_, w| w.pin_intx().clear_bit());
+            }
+        });
+    }
+}
+
+impl<const P: char, const N: u8, PULL> Wait for Pin<P, N, Input<PULL>> {
+    async fn wait_for_high(&mut self) -> Result<(), Self::Error> {
+        self.wait_for(Trigger::High).await;
+        Ok(())
+    }
+
+    async fn wait_for_low(&mut self) -> Result<(), Self::Error> {
+        self.wait_for(Trigger::Low).await;
+        Ok(())
+    }
+
+    async fn wait_for_rising_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_for(Trigger::Rising).await;
+        Ok(())
+    }
+
+    async fn wait_for_falling_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_for(Trigger::Falling).await;
+        Ok(())
+    }
+
+    async fn wait_for_any_edge(&mut self) -> Result<(), Self::Error> {
+        // There are no double edge interrupts, waiting for the opposite level
+        // also catches a change right before arming
+        if self.is_high()? {
+            self.wait_for(Trigger::Low).await;
+        } else {
+            self.wait_for(Trigger::High).await;
+        }
+        Ok(())
+    }
+}
+
+fn on_interrupt(port: char) {
+    let pending = port!(port, |regs| unsafe {
+        let pending = regs.int_if().read().bits() & regs.int_en().read().bits();
+        regs.int_if().write(|w| w.bits(pending));
+        regs.int_en().modify(|r, w| w.bits(r.bits() & !pending));
+        pending
+    });
+
+    for (bit, waker) in WAKERS[(port == 'B') as usize].iter().enumerate() {
+        if pending & 1 << bit != 0 {
+            waker.wake();
+        }
+    }
+}
+
+#[riscv_rt::external_interrupt(ExternalInterrupt::GPIOA)]
+fn gpioa() {
+    on_interrupt('A');
+}
+
+#[riscv_rt::external_interrupt(ExternalInterrupt::GPIOB)]
+fn gpiob() {
+    on_interrupt('B');
+}
+
+pub trait GpioExt {
+    type Parts;
+
+    fn split(self) -> Self::Parts;
+}
+
+macro_rules! gpio {
+    ($gpiox:ident, $Gpiox:ident, $port:literal, [$($pxi:ident: $i:literal),+]) => {
+        pub mod $gpiox {
+            use super::{GpioExt, Pin};
+
+            pub struct Parts {
+                $(pub $pxi: Pin<$port, $i>,)+
+            }
+
+            impl GpioExt for pac::$Gpiox {
+                type Parts = Parts;
+
+                fn split(self) -> Parts {
+                    Parts {
+                        $($pxi: Pin::new(),)+
+                    }
+                }
+            }
+        }
+    };
+}
+
+gpio!(gpioa, Gpioa, 'A', [
+    pa0: 0, pa1: 1, pa2: 2, pa3: 3, pa4: 4, pa5: 5, pa6: 6, pa7: 7,
+    pa8: 8, pa9: 9, pa10: 10, pa11: 11, pa12: 12, pa13: 13, pa14: 14, pa15: 15
+]);
+
+gpio!(gpiob, Gpiob, 'B', [
+    pb0: 0, pb1: 1, pb2: 2, pb3: 3, pb4: 4, pb5: 5, pb6: 6, pb7: 7,
+    pb8: 8, pb9: 9, pb10: 10, pb11: 11, pb12: 12, pb13: 13, pb14: 14, pb15: 15,
+    pb16: 16, pb17: 17, pb18: 18, pb19: 19, pb20: 20, pb21: 21, pb22: 22, pb23: 23
+]);
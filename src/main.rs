@@ -5,22 +5,51 @@ pub extern crate ch58x as pac;
 pub extern crate embedded_hal as hal;
 pub extern crate riscv;
 
+mod adc;
+mod battery;
+mod executor;
+mod gpio;
 mod pfic;
+mod power;
+mod pwmx;
+mod remap;
+mod reset;
+mod rtc;
+#[cfg(feature = "time-driver-rtc")]
+mod rtcclk;
 mod sys;
+#[cfg(not(feature = "time-driver-rtc"))]
 mod sysclk;
+mod timer;
+mod touchkey;
+mod watchdog;
+mod ws2812;
 
 use embassy_executor::Spawner;
+use fugit::HertzU32;
 
 use crate::sys::{Config, SysExt};
 
-#[embassy_executor::main]
-async fn main(_spawner: Spawner) -> ! {
-    let peripherals = pac::Peripherals::take().unwrap();
+const CONFIG: Config = Config::pll_hz(HertzU32::MHz(60));
 
-    let sys = peripherals.sys.set(Config::pll(8));
-    sysclk::init(peripherals.systick, &sys, &peripherals.pfic);
+#[embassy_executor::main(executor = "crate::executor::Executor")]
+async fn main(_spawner: Spawner) {
+    let peripherals = pac::Peripherals::take().unwrap();
 
-    loop {}
+    let clocks = peripherals.sys.freeze(CONFIG);
+    peripherals.sys.calibrate_lsi(&clocks);
+    #[cfg(not(feature = "time-driver-rtc"))]
+    {
+        sysclk::init(peripherals.systick, &clocks, &peripherals.pfic);
+        #[cfg(feature = "sleep")]
+        sysclk::enable_sleep(&clocks);
+    }
+    #[cfg(feature = "time-driver-rtc")]
+    {
+        rtcclk::init(&clocks, &peripherals.pfic);
+        #[cfg(feature = "sleep")]
+        rtcclk::enable_sleep();
+    }
 }
 
 #[panic_handler]
@@ -0,0 +1,253 @@
+use crate::sys::{self, with_safe_mode};
+use core::sync::atomic::{AtomicU32, Ordering};
+use pac::Sys;
+use riscv::asm::{nop, wfi};
+
+const PWR_PLAN_EN: u16 = 1 << 15;
+const PWR_MUST_0010: u16 = 0b0010 << 11;
+const PWR_RAM30K: u16 = 1 << 4;
+const PWR_EXTEND: u16 = 1 << 3;
+const PWR_CORE: u16 = 1 << 2;
+const PWR_RAM2K: u16 = 1 << 1;
+
+static STAY_AWAKE: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Clone, Copy, Default)]
+pub struct WakeSources {
+    pub usb: bool,
+    pub usb2: bool,
+    pub rtc: bool,
+    pub gpio: bool,
+    pub bat: bool,
+}
+
+#[derive(Clone, Copy)]
+pub struct Retention {
+    /// 2 KB retention SRAM
+    pub ram2k: bool,
+    /// 30 KB main SRAM
+    pub ram30k: bool,
+    /// USB and BLE
+    pub extend: bool,
+}
+
+impl Retention {
+    pub const fn all() -> Self {
+        Self {
+            ram2k: true,
+            ram30k: true,
+            extend: true,
+        }
+    }
+
+    pub const fn none() -> Self {
+        Self {
+            ram2k: false,
+            ram30k: false,
+            extend: false,
+        }
+    }
+
+    fn bits(&self) -> u16 {
+        let mut bits = 0;
+        if self.ram2k {
+            bits |= PWR_RAM2K;
+        }
+        if self.ram30k {
+            bits |= PWR_RAM30K;
+        }
+        if self.extend {
+            bits |= PWR_EXTEND;
+        }
+        bits
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Peripheral {
+    Tmr0 = 0,
+    Tmr1 = 1,
+    Tmr2 = 2,
+    Tmr3 = 3,
+    Uart0 = 4,
+    Uart1 = 5,
+    Uart2 = 6,
+    Uart3 = 7,
+    Spi0 = 8,
+    Spi1 = 9,
+    Pwmx = 10,
+    I2c = 11,
+    Usb = 12,
+    Ble = 15,
+}
+
+/// Keeps the time driver from entering sleep while held, for waits on
+/// peripherals that stop along with the HSE.
+pub(crate) struct StayAwake {
+    _private: (),
+}
+
+impl StayAwake {
+    pub(crate) fn new() -> Self {
+        STAY_AWAKE.fetch_add(1, Ordering::SeqCst);
+        Self { _private: () }
+    }
+}
+
+impl Drop for StayAwake {
+    fn drop(&mut self) {
+        STAY_AWAKE.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// No driver holds a [`StayAwake`].
+pub(crate) fn may_sleep() -> bool {
+    STAY_AWAKE.load(Ordering::SeqCst) == 0
+}
+
+pub trait PowerExt {
+    fn set_wake_sources(&self, sources: WakeSources);
+    fn set_clock_enabled(&self, peripheral: Peripheral, enabled: bool);
+    fn idle(&self);
+    fn halt(&self);
+    /// Keeps the 32 kHz clock and the `retention` memories powered, the
+    /// frozen clock config is restored after waking up.
+    fn sleep(&self, retention: Retention);
+    fn shutdown(&self, retention: Retention) -> !;
+}
+
+impl PowerExt for Sys {
+    fn set_wake_sources(&self, sources: WakeSources) {
+        with_safe_mode(|| {
+            self.slp_wake_ctrl().write(|w| {
+                w.slp_usb_wake()
+                    .bit(sources.usb)
+                    .slp_usb2_wake()
+                    .bit(sources.usb2)
+                    .slp_rtc_wake()
+                    .bit(sources.rtc)
+                    .slp_gpio_wake()
+                    .bit(sources.gpio)
+                    .slp_bat_wake()
+                    .bit(sources.bat)
+                    .wake_ev_mode()
+                    .set_bit()
+            });
+        });
+    }
+
+    fn set_clock_enabled(&self, peripheral: Peripheral, enabled: bool) {
+        let bit = peripheral as u8;
+        let update = |bits: u8, mask: u8| if enabled { bits & !mask } else { bits | mask };
+        with_safe_mode(|| {
+            if bit < 8 {
+                self.slp_clk_off0()
+                    .modify(|r, w| unsafe { w.bits(update(r.bits(), 1 << bit)) });
+            } else {
+                self.slp_clk_off1()
+                    .modify(|r, w| unsafe { w.bits(update(r.bits(), 1 << (bit - 8))) });
+            }
+        });
+    }
+
+    fn idle(&self) {
+        unsafe { pac::Pfic::steal() }
+            .sctlr()
+            .modify(|_, w| w.sleepdeep().clear_bit());
+        wfi();
+    }
+
+    fn halt(&self) {
+        let saved = enter_deep_sleep(self);
+        wfi();
+        nop();
+        nop();
+        leave_deep_sleep(self, saved);
+    }
+
+    fn sleep(&self, retention: Retention) {
+        let saved = enter_deep_sleep(self);
+        with_safe_mode(|| {
+            self.slp_power_ctrl()
+                .modify(|_, w| w.ram_ret_lv().set_bit());
+            self.power_plan().write(|w| unsafe {
+                w.bits(PWR_PLAN_EN | PWR_MUST_0010 | PWR_CORE | retention.bits())
+            });
+        });
+        sys::set_sleep_clocks(self);
+
+        wfi();
+        nop();
+        nop();
+
+        leave_deep_sleep(self, saved);
+        sys::restore_clocks(self);
+    }
+
+    fn shutdown(&self, retention: Retention) -> ! {
+        enter_deep_sleep(self);
+        sys::set_sleep_clocks(self);
+        with_safe_mode(|| {
+            self.slp_power_ctrl()
+                .modify(|_, w| w.ram_ret_lv().set_bit());
+            self.power_plan()
+                .write(|w| unsafe { w.bits(PWR_PLAN_EN | PWR_MUST_0010 | retention.bits()) });
+        });
+
+        wfi();
+        nop();
+        nop();
+
+        // Waking up from shutdown is a reset, if the sleep was aborted force
+        // one
+        with_safe_mode(|| {
+            self.rst_wdog_ctrl()
+                .modify(|_, w| w.software_reset().set_bit());
+        });
+        loop {
+            wfi();
+        }
+    }
+}
+
+/// Raises the HSE bias for a reliable restart and lowers the LSE drive once
+/// it has settled, returns the tune values and battery detection to restore
+/// after waking up.
+fn enter_deep_sleep(sys: &Sys) -> (u8, u8, u8) {
+    let xt32k_tune = sys.xt32k_tune().read().bits();
+    let xt32m_tune = sys.xt32m_tune().read().bits();
+    let bat_det_ctrl = sys.bat_det_ctrl().read().bits();
+    let lse_settled = sys.rtc_cnt_32k().read().bits() > 0x3FFF;
+
+    with_safe_mode(|| {
+        // Battery detection is only kept on if it is a wake source
+        if sys.slp_wake_ctrl().read().slp_bat_wake().bit_is_clear() {
+            sys.bat_det_ctrl().write(|w| unsafe { w.bits(0) });
+        }
+        if lse_settled {
+            sys.xt32k_tune()
+                .modify(|_, w| unsafe { w.xt32k_i_tune().bits(0b01) });
+        }
+        sys.xt32m_tune()
+            .modify(|_, w| unsafe { w.xt32m_i_bias().bits(0b11) });
+    });
+    unsafe { pac::Pfic::steal() }
+        .sctlr()
+        .modify(|_, w| w.sleepdeep().set_bit());
+
+    (xt32k_tune, xt32m_tune, bat_det_ctrl)
+}
+
+fn leave_deep_sleep(sys: &Sys, (xt32k_tune, xt32m_tune, bat_det_ctrl): (u8, u8, u8)) {
+    // Otherwise every later wfi would halt as well
+    unsafe { pac::Pfic::steal() }
+        .sctlr()
+        .modify(|_, w| w.sleepdeep().clear_bit());
+    with_safe_mode(|| {
+        sys.xt32k_tune().write(|w| unsafe { w.bits(xt32k_tune) });
+        sys.xt32m_tune().write(|w| unsafe { w.bits(xt32m_tune) });
+        // Otherwise a BatteryMonitor would stop after the first sleep
+        sys.bat_det_ctrl()
+            .write(|w| unsafe { w.bits(bat_det_ctrl) });
+    });
+}
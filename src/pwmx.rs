@@ -0,0 +1,213 @@
+use crate::{
+    pfic::PficExt,
+    power::StayAwake,
+    remap::{PwmxPin, Remap},
+};
+use core::{
+    convert::Infallible,
+    future::poll_fn,
+    sync::atomic::{AtomicBool, Ordering},
+    task::Poll,
+};
+use embassy_sync::waitqueue::AtomicWaker;
+use hal::pwm::{ErrorType, SetDutyCycle};
+use pac::{
+    Pfic,
+    interrupt::{ExternalInterrupt, Priority},
+};
+
+static CYC_FIRED: AtomicBool = AtomicBool::new(false);
+static CYC_WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Bits of the duty cycle, a period is 2^bits - 1 PWM clocks
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Width {
+    Bits8 = 0,
+    Bits7 = 1,
+    Bits6 = 2,
+    Bits5 = 3,
+}
+
+impl Width {
+    const fn max_duty(self) -> u8 {
+        (0xFF >> self as u8) as u8
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    pub width: Width,
+    /// Fsys divider for the PWM clock, 0 is 256
+    pub clock_div: u8,
+    /// PWM4/5, PWM6/7, PWM8/9 and PWM10/11 take turns instead of starting
+    /// their periods together, at twice the period
+    pub stagger: [bool; 4],
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            width: Width::Bits8,
+            clock_div: 4,
+            stagger: [false; 4],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+/// The PWM4 to PWM11 block, sharing one clock and period. PWM4, 5, 7, 8
+/// and 9 are all on either their default pins or, with `REMAP`, their
+/// alternate ones.
+pub struct Pwmx<const REMAP: bool = false> {
+    pwmx: pac::Pwmx,
+    max_duty: u8,
+}
+
+impl<const REMAP: bool> Pwmx<REMAP> {
+    pub fn new(pwmx: pac::Pwmx, config: Config, pfic: &Pfic) -> Self {
+        pwmx.pwm_clock_div()
+            .write(|w| unsafe { w.pwm_clock_div().bits(config.clock_div) });
+        // Periods of 2^bits - 1 clocks, so the maximum duty is always on
+        pwmx.pwm_config().write(|w| unsafe {
+            w.pwm_cycle_sel()
+                .set_bit()
+                .pwm_cyc_mod()
+                .bits(config.width as u8)
+                .pwm4_5_stag_en()
+                .bit(config.stagger[0])
+                .pwm6_7_stag_en()
+                .bit(config.stagger[1])
+                .pwm8_9_stag_en()
+                .bit(config.stagger[2])
+                .pwm10_11_stag_en()
+                .bit(config.stagger[3])
+        });
+
+        pac::Pwmx::set_remap(REMAP);
+
+        pfic.set_priority(ExternalInterrupt::PWMx, Priority::P15);
+        pfic.enable(ExternalInterrupt::PWMx);
+
+        Self {
+            pwmx,
+            max_duty: config.width.max_duty(),
+        }
+    }
+
+    /// Starts the output of PWM`CH` on `pin`, which has to be a push-pull
+    /// output on the placement of the block.
+    pub fn channel<const CH: u8, PIN: PwmxPin<CH>>(
+        &mut self,
+        pin: PIN,
+        polarity: Polarity,
+    ) -> Channel<CH, PIN> {
+        const {
+            assert!(
+                on_placement(PIN::REMAP, REMAP),
+                "pin is on the other placement of PWMx"
+            )
+        };
+
+        let mask = 1 << (CH - 4);
+        let mut channel = Channel {
+            pin,
+            max_duty: self.max_duty,
+        };
+        let _ = channel.set_duty_cycle(0);
+        critical_section::with(|_| unsafe {
+            self.pwmx.pwm_polar().modify(|r, w| {
+                w.bits(if polarity == Polarity::ActiveLow {
+                    r.bits() | mask
+                } else {
+                    r.bits() & !mask
+                })
+            });
+            self.pwmx
+                .pwm_out_en()
+                .modify(|r, w| w.bits(r.bits() | mask));
+        });
+        channel
+    }
+
+    /// Waits for the end of the current period, after which duty cycles set
+    /// right away all take effect together with the next one.
+    pub async fn wait_cycle_end(&mut self) {
+        let _awake = StayAwake::new();
+        CYC_FIRED.store(false, Ordering::SeqCst);
+        self.pwmx
+            .pwm_int_ctrl()
+            .write(|w| w.pwm_if_cyc().set_bit().pwm_ie_cyc().set_bit());
+
+        poll_fn(|cx| {
+            CYC_WAKER.register(cx.waker());
+            if CYC_FIRED.swap(false, Ordering::SeqCst) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+}
+
+const fn on_placement(pin: Option<bool>, remap: bool) -> bool {
+    match pin {
+        Some(pin) => pin == remap,
+        None => true,
+    }
+}
+
+pub struct Channel<const CH: u8, PIN> {
+    pin: PIN,
+    max_duty: u8,
+}
+
+impl<const CH: u8, PIN> Channel<CH, PIN> {
+    /// Stops the output and gives back the pin.
+    pub fn free(self) -> PIN {
+        let pwmx = unsafe { pac::Pwmx::steal() };
+        critical_section::with(|_| {
+            pwmx.pwm_out_en()
+                .modify(|r, w| unsafe { w.bits(r.bits() & !(1 << (CH - 4))) });
+        });
+        self.pin
+    }
+}
+
+impl<const CH: u8, PIN> ErrorType for Channel<CH, PIN> {
+    type Error = Infallible;
+}
+
+impl<const CH: u8, PIN> SetDutyCycle for Channel<CH, PIN> {
+    fn max_duty_cycle(&self) -> u16 {
+        self.max_duty as u16
+    }
+
+    fn set_duty_cycle(&mut self, duty: u16) -> Result<(), Self::Error> {
+        // The data registers of all channels follow each other
+        let pwmx = unsafe { pac::Pwmx::steal() };
+        let data = pwmx.pwm4_data().as_ptr() as *mut u8;
+        unsafe {
+            data.add(CH as usize - 4)
+                .write_volatile(duty.min(self.max_duty as u16) as u8)
+        };
+        Ok(())
+    }
+}
+
+#[riscv_rt::external_interrupt(ExternalInterrupt::PWMx)]
+fn pwmx() {
+    let pwmx = unsafe { pac::Pwmx::steal() };
+    if pwmx.pwm_int_ctrl().read().pwm_if_cyc().bit_is_set() {
+        // Also clears the flag
+        pwmx.pwm_int_ctrl().write(|w| w.pwm_if_cyc().set_bit());
+
+        CYC_FIRED.store(true, Ordering::SeqCst);
+        CYC_WAKER.wake();
+    }
+}
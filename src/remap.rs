@@ -0,0 +1,204 @@
+use crate::gpio::Pin;
+use pac::{I2c, Pwmx, Spi0, Sys, Tmr0, Tmr1, Tmr2, Tmr3, Uart0, Uart1, Uart2, Uart3};
+
+/// Peripherals that can be moved to alternate pins by a `pin_alternate` bit
+pub trait Remap {
+    fn set_remap(remap: bool);
+}
+
+/// A complete pin set for `PERIPH`, which all have to be on either the
+/// default or the alternate pins.
+pub trait Pins<PERIPH> {
+    const REMAP: bool;
+}
+
+/// Selects the placement of `PINS` for `PERIPH`, meant to be called by
+/// drivers taking their pins.
+pub fn select<PERIPH: Remap, PINS: Pins<PERIPH>>(_pins: &PINS) {
+    PERIPH::set_remap(PINS::REMAP);
+}
+
+/// Inverts the levels on RXD0/TXD0.
+pub fn set_uart0_inverted(inverted: bool) {
+    let sys = unsafe { Sys::steal() };
+    critical_section::with(|_| {
+        sys.pin_alternate()
+            .modify(|_, w| w.pin_u0_inv().bit(inverted));
+    });
+}
+
+macro_rules! remap {
+    ($($PERIPH:ident: $field:ident),+) => {
+        $(
+            impl Remap for $PERIPH {
+                fn set_remap(remap: bool) {
+                    let sys = unsafe { Sys::steal() };
+                    critical_section::with(|_| {
+                        sys.pin_alternate().modify(|_, w| w.$field().bit(remap));
+                    });
+                }
+            }
+        )+
+    };
+}
+
+remap!(
+    Uart0: pin_uart0,
+    Uart1: pin_uart1,
+    Uart2: pin_uart2,
+    Uart3: pin_uart3,
+    Spi0: pin_spi0,
+    I2c: pin_i2c,
+    Pwmx: pin_pwmx,
+    Tmr0: pin_tmr0,
+    Tmr1: pin_tmr1,
+    Tmr2: pin_tmr2,
+    Tmr3: pin_tmr3
+);
+
+/// Marks the modem pins of UART0, which are remapped separately
+pub struct Modem;
+
+remap!(Modem: pin_modem);
+
+macro_rules! pin_trait {
+    ($($Trait:ident),+) => {
+        $(
+            pub trait $Trait<PERIPH> {
+                const REMAP: bool;
+            }
+        )+
+    };
+}
+
+pin_trait!(
+    TxPin, RxPin, SckPin, MosiPin, MisoPin, CsPin, SclPin, SdaPin, TimerPin, DsrPin, DtrPin
+);
+
+/// Output of PWMx channel `CH`, PWM6, PWM10 and PWM11 can't be moved.
+pub trait PwmxPin<const CH: u8> {
+    const REMAP: Option<bool>;
+}
+
+macro_rules! pins {
+    ($($Trait:ident<$PERIPH:ident>: $port:literal $n:literal, $alt_port:literal $alt_n:literal;)+) => {
+        $(
+            impl<MODE> $Trait<$PERIPH> for Pin<$port, $n, MODE> {
+                const REMAP: bool = false;
+            }
+
+            impl<MODE> $Trait<$PERIPH> for Pin<$alt_port, $alt_n, MODE> {
+                const REMAP: bool = true;
+            }
+        )+
+    };
+}
+
+pins! {
+    TxPin<Uart0>: 'B' 7, 'A' 14;
+    RxPin<Uart0>: 'B' 4, 'A' 15;
+    TxPin<Uart1>: 'A' 9, 'B' 13;
+    RxPin<Uart1>: 'A' 8, 'B' 12;
+    TxPin<Uart2>: 'A' 7, 'B' 23;
+    RxPin<Uart2>: 'A' 6, 'B' 22;
+    TxPin<Uart3>: 'A' 5, 'B' 21;
+    RxPin<Uart3>: 'A' 4, 'B' 20;
+    CsPin<Spi0>: 'A' 12, 'B' 12;
+    SckPin<Spi0>: 'A' 13, 'B' 13;
+    MosiPin<Spi0>: 'A' 14, 'B' 14;
+    MisoPin<Spi0>: 'A' 15, 'B' 15;
+    SclPin<I2c>: 'B' 13, 'B' 21;
+    SdaPin<I2c>: 'B' 12, 'B' 20;
+    TimerPin<Tmr0>: 'A' 9, 'B' 23;
+    TimerPin<Tmr1>: 'A' 10, 'B' 10;
+    TimerPin<Tmr2>: 'A' 11, 'B' 11;
+    TimerPin<Tmr3>: 'A' 2, 'B' 22;
+    DsrPin<Modem>: 'B' 1, 'B' 14;
+    DtrPin<Modem>: 'B' 5, 'B' 15;
+}
+
+macro_rules! pwmx_pins {
+    ($($ch:literal: $port:literal $n:literal $(, $alt_port:literal $alt_n:literal)?;)+) => {
+        $(
+            impl<MODE> PwmxPin<$ch> for Pin<$port, $n, MODE> {
+                const REMAP: Option<bool> = pwmx_pins!(@remap $($alt_n)?);
+            }
+
+            $(
+                impl<MODE> PwmxPin<$ch> for Pin<$alt_port, $alt_n, MODE> {
+                    const REMAP: Option<bool> = Some(true);
+                }
+            )?
+        )+
+    };
+    (@remap) => { None };
+    (@remap $alt_n:literal) => { Some(false) };
+}
+
+pwmx_pins! {
+    4: 'A' 12, 'A' 6;
+    5: 'A' 13, 'A' 7;
+    6: 'B' 0;
+    7: 'B' 4, 'B' 1;
+    8: 'B' 6, 'B' 2;
+    9: 'B' 7, 'B' 3;
+    10: 'B' 14;
+    11: 'B' 23;
+}
+
+macro_rules! uart_pins {
+    ($($UART:ident),+) => {
+        $(
+            impl<TX: TxPin<$UART>, RX: RxPin<$UART>> Pins<$UART> for (TX, RX) {
+                const REMAP: bool = {
+                    assert!(TX::REMAP == RX::REMAP, "TX and RX are on different placements");
+                    TX::REMAP
+                };
+            }
+        )+
+    };
+}
+
+uart_pins!(Uart0, Uart1, Uart2, Uart3);
+
+impl<SCK: SckPin<Spi0>, MOSI: MosiPin<Spi0>, MISO: MisoPin<Spi0>> Pins<Spi0> for (SCK, MOSI, MISO) {
+    const REMAP: bool = {
+        assert!(
+            SCK::REMAP == MOSI::REMAP && SCK::REMAP == MISO::REMAP,
+            "SPI pins are on different placements"
+        );
+        SCK::REMAP
+    };
+}
+
+impl<SCL: SclPin<I2c>, SDA: SdaPin<I2c>> Pins<I2c> for (SCL, SDA) {
+    const REMAP: bool = {
+        assert!(
+            SCL::REMAP == SDA::REMAP,
+            "SCL and SDA are on different placements"
+        );
+        SCL::REMAP
+    };
+}
+
+impl<DSR: DsrPin<Modem>, DTR: DtrPin<Modem>> Pins<Modem> for (DSR, DTR) {
+    const REMAP: bool = {
+        assert!(
+            DSR::REMAP == DTR::REMAP,
+            "DSR and DTR are on different placements"
+        );
+        DSR::REMAP
+    };
+}
+
+macro_rules! timer_pins {
+    ($($TMR:ident),+) => {
+        $(
+            impl<PIN: TimerPin<$TMR>> Pins<$TMR> for PIN {
+                const REMAP: bool = PIN::REMAP;
+            }
+        )+
+    };
+}
+
+timer_pins!(Tmr0, Tmr1, Tmr2, Tmr3);
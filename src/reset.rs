@@ -0,0 +1,91 @@
+use crate::sys::with_safe_mode;
+use pac::Sys;
+use riscv::asm::wfi;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResetReason {
+    PowerOn,
+    Software,
+    Watchdog,
+    /// Reset pin
+    Manual,
+    /// Woken up from shutdown
+    Shutdown,
+}
+
+/// Cause of the most recent reset.
+///
+/// The flag is latched by hardware and only changes on the next reset, so
+/// this works at any time without taking the peripherals, including before
+/// `main` and in the panic handler.
+pub fn reset_reason() -> ResetReason {
+    let sys = unsafe { Sys::steal() };
+    match sys
+        .reset_status_r8_glob_rom_cfg()
+        .read()
+        .reset_flag()
+        .bits()
+    {
+        0b000 => ResetReason::Software,
+        0b001 => ResetReason::PowerOn,
+        0b010 => ResetReason::Watchdog,
+        0b011 => ResetReason::Manual,
+        0b101 => ResetReason::Shutdown,
+        // Same causes, but hit while waking up
+        0b100 => ResetReason::Software,
+        0b110 => ResetReason::Watchdog,
+        _ => ResetReason::Manual,
+    }
+}
+
+pub fn software_reset() -> ! {
+    let sys = unsafe { Sys::steal() };
+    with_safe_mode(|| {
+        sys.rst_wdog_ctrl()
+            .modify(|_, w| w.software_reset().set_bit());
+    });
+
+    loop {
+        wfi();
+    }
+}
+
+/// Packed into the single byte of `glob_reset_keep`, which is only cleared by
+/// a power-on reset. Larger buffers can go into the `.uninit` section.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RetainedState {
+    /// Wraps at 64
+    pub boot_count: u8,
+    pub crashed: bool,
+    pub dfu_requested: bool,
+}
+
+impl RetainedState {
+    const BOOT_COUNT_MASK: u8 = 0x3F;
+    const CRASHED: u8 = 1 << 6;
+    const DFU_REQUESTED: u8 = 1 << 7;
+
+    pub fn load() -> Self {
+        let sys = unsafe { Sys::steal() };
+        let bits = sys.glob_reset_keep().read().glob_reset_keep().bits();
+        Self {
+            boot_count: bits & Self::BOOT_COUNT_MASK,
+            crashed: bits & Self::CRASHED != 0,
+            dfu_requested: bits & Self::DFU_REQUESTED != 0,
+        }
+    }
+
+    pub fn store(&self) {
+        let mut bits = self.boot_count & Self::BOOT_COUNT_MASK;
+        if self.crashed {
+            bits |= Self::CRASHED;
+        }
+        if self.dfu_requested {
+            bits |= Self::DFU_REQUESTED;
+        }
+
+        let sys = unsafe { Sys::steal() };
+        sys.glob_reset_keep()
+            .write(|w| unsafe { w.glob_reset_keep().bits(bits) });
+    }
+}
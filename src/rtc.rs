@@ -0,0 +1,211 @@
+use crate::{
+    pfic::PficExt,
+    sys::{Clocks, with_safe_mode},
+};
+pub use badgick_core::datetime::{DateTime, InvalidDateTime};
+use core::{
+    cell::Cell,
+    future::poll_fn,
+    sync::atomic::{AtomicBool, Ordering},
+    task::Poll,
+};
+use critical_section::CriticalSection;
+use embassy_sync::{
+    blocking_mutex::{Mutex, raw::CriticalSectionRawMutex},
+    waitqueue::AtomicWaker,
+};
+use pac::{
+    Pfic, Sys,
+    interrupt::{ExternalInterrupt, Priority},
+};
+
+/// 32 kHz count at which the day counter is incremented
+pub(crate) const MAX_CNT: u32 = 0xA8C0_0000;
+
+/// Users of the single trigger comparator, the earliest one is programmed
+#[derive(Clone, Copy)]
+pub(crate) enum Trigger {
+    TimeDriver = 0,
+    Alarm = 1,
+}
+
+static TRIGGERS: Mutex<CriticalSectionRawMutex, Cell<[u64; 2]>> =
+    Mutex::new(Cell::new([u64::MAX; 2]));
+static ALARM_PERIOD: Mutex<CriticalSectionRawMutex, Cell<u64>> = Mutex::new(Cell::new(0));
+static ALARM_FIRED: AtomicBool = AtomicBool::new(false);
+static ALARM_WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Wall-clock time on top of the free-running RTC counter, which is never
+/// reloaded so the time drivers stay monotonic.
+pub struct Rtc {
+    clk32k: u64,
+    offset: i64,
+}
+
+impl Rtc {
+    pub fn new(clocks: &Clocks, pfic: &Pfic) -> Self {
+        pfic.set_priority(ExternalInterrupt::RTC, Priority::P15);
+        pfic.enable(ExternalInterrupt::RTC);
+
+        Self {
+            clk32k: clocks.clk32k().to_Hz() as u64,
+            offset: 0,
+        }
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        (cnt_total(&unsafe { Sys::steal() }) as i64 + self.offset) as u64 / self.clk32k
+    }
+
+    pub fn set_timestamp(&mut self, timestamp: u64) {
+        self.offset = (timestamp * self.clk32k) as i64 - cnt_total(&unsafe { Sys::steal() }) as i64;
+    }
+
+    pub fn datetime(&self) -> DateTime {
+        DateTime::from_timestamp(self.timestamp())
+    }
+
+    pub fn set_datetime(&mut self, datetime: DateTime) -> Result<(), InvalidDateTime> {
+        if !datetime.is_valid() {
+            return Err(InvalidDateTime);
+        }
+
+        self.set_timestamp(datetime.timestamp());
+        Ok(())
+    }
+
+    /// Fires once at `timestamp`, or every `period` seconds from then on.
+    pub fn set_alarm(&mut self, timestamp: u64, period: Option<u32>) {
+        let at_cnt = ((timestamp * self.clk32k) as i64 - self.offset).max(0) as u64;
+        let period_cnt = period.map_or(0, |period| period as u64 * self.clk32k);
+        critical_section::with(|cs| {
+            ALARM_FIRED.store(false, Ordering::SeqCst);
+            ALARM_PERIOD.borrow(cs).set(period_cnt);
+            set_trigger(cs, Trigger::Alarm, at_cnt);
+        });
+    }
+
+    pub fn clear_alarm(&mut self) {
+        critical_section::with(|cs| {
+            ALARM_FIRED.store(false, Ordering::SeqCst);
+            clear_trigger(cs, Trigger::Alarm);
+        });
+    }
+
+    pub async fn wait_alarm(&mut self) {
+        poll_fn(|cx| {
+            ALARM_WAKER.register(cx.waker());
+            if ALARM_FIRED.swap(false, Ordering::SeqCst) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+}
+
+/// 32 kHz cycles since the start of the current day
+fn cnt(sys: &Sys) -> u32 {
+    // Both halves can't be read at once, repeat until they agree
+    let read = || unsafe { (sys.rtc_cnt_32k().as_ptr() as *const u32).read_volatile() };
+    let mut cnt = read();
+    loop {
+        let next = read();
+        if next == cnt {
+            return cnt;
+        }
+        cnt = next;
+    }
+}
+
+/// 32 kHz cycles since the day counter was last reset
+pub(crate) fn cnt_total(sys: &Sys) -> u64 {
+    loop {
+        let day = sys.rtc_cnt_day().read().rtc_cnt_day().bits();
+        let cnt = cnt(sys);
+        if sys.rtc_cnt_day().read().rtc_cnt_day().bits() == day {
+            return day as u64 * MAX_CNT as u64 + cnt as u64;
+        }
+    }
+}
+
+/// Requests the RTC interrupt at `at_cnt` (see [`cnt_total`]), which also
+/// wakes up from sleep.
+pub(crate) fn set_trigger(cs: CriticalSection, trigger: Trigger, at_cnt: u64) {
+    let triggers = TRIGGERS.borrow(cs);
+    let mut at = triggers.get();
+    at[trigger as usize] = at_cnt;
+    triggers.set(at);
+
+    update_trigger(cs);
+}
+
+pub(crate) fn clear_trigger(cs: CriticalSection, trigger: Trigger) {
+    set_trigger(cs, trigger, u64::MAX);
+}
+
+fn update_trigger(cs: CriticalSection) {
+    let sys = unsafe { Sys::steal() };
+
+    let next_cnt = TRIGGERS.borrow(cs).get().into_iter().min().unwrap();
+    if next_cnt == u64::MAX {
+        with_safe_mode(|| {
+            sys.rtc_mode_ctrl()
+                .modify(|_, w| w.rtc_trig_en().clear_bit());
+        });
+        sys.rtc_flag_ctrl().write(|w| w.rtc_trig_clr().set_bit());
+        return;
+    }
+
+    // The comparator only sees the count within a day, triggers further out
+    // go through an intermediate one
+    let cnt = cnt_total(&sys);
+    let trig_cnt = next_cnt.max(cnt + 1).min(cnt + MAX_CNT as u64 / 2);
+    with_safe_mode(|| {
+        sys.rtc_trig()
+            .write(|w| unsafe { w.rtc_trig().bits((trig_cnt % MAX_CNT as u64) as u32) });
+        sys.rtc_mode_ctrl().modify(|_, w| w.rtc_trig_en().set_bit());
+    });
+
+    // Passed while programming, the comparator won't match anymore
+    if trig_cnt <= cnt_total(&sys) {
+        unsafe { Pfic::steal() }.pend(ExternalInterrupt::RTC);
+    }
+}
+
+#[riscv_rt::external_interrupt(ExternalInterrupt::RTC)]
+fn rtc() {
+    critical_section::with(|cs| {
+        let sys = unsafe { Sys::steal() };
+        sys.rtc_flag_ctrl().write(|w| w.rtc_trig_clr().set_bit());
+
+        let cnt = cnt_total(&sys);
+        let triggers = TRIGGERS.borrow(cs);
+
+        let mut at = triggers.get();
+        if at[Trigger::TimeDriver as usize] <= cnt {
+            at[Trigger::TimeDriver as usize] = u64::MAX;
+            triggers.set(at);
+
+            #[cfg(feature = "time-driver-rtc")]
+            crate::rtcclk::trigger_alarm(cs);
+        }
+
+        let mut at = triggers.get();
+        if at[Trigger::Alarm as usize] <= cnt {
+            let period = ALARM_PERIOD.borrow(cs).get();
+            at[Trigger::Alarm as usize] = if period != 0 {
+                at[Trigger::Alarm as usize] + period
+            } else {
+                u64::MAX
+            };
+            triggers.set(at);
+
+            ALARM_FIRED.store(true, Ordering::SeqCst);
+            ALARM_WAKER.wake();
+        }
+
+        update_trigger(cs);
+    });
+}
@@ -0,0 +1,158 @@
+use crate::{
+    pfic::PficExt,
+    power::{self, PowerExt, Retention},
+    rtc::{self, Trigger},
+    sys::{Clocks, with_safe_mode},
+};
+use core::{
+    cell::{Cell, RefCell},
+    sync::atomic::{AtomicU32, Ordering},
+};
+use critical_section::CriticalSection;
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time_driver::{Driver, TICK_HZ};
+use embassy_time_queue_utils::Queue;
+use pac::{
+    Pfic, Sys,
+    interrupt::{ExternalInterrupt, Priority},
+};
+
+/// Shortest time worth entering sleep for, in 32 kHz cycles
+const MIN_SLEEP_CNT: u64 = 96;
+
+pub struct RtcDriver {
+    clk32k: AtomicU32,
+    alarm_cnt: Mutex<CriticalSectionRawMutex, Cell<u64>>,
+    queue: Mutex<CriticalSectionRawMutex, RefCell<Queue>>,
+    sleep_enabled: Mutex<CriticalSectionRawMutex, Cell<bool>>,
+}
+
+embassy_time_driver::time_driver_impl!(static DRIVER: RtcDriver = RtcDriver {
+    clk32k: AtomicU32::new(0),
+    alarm_cnt: Mutex::new(Cell::new(u64::MAX)),
+    queue: Mutex::new(RefCell::new(Queue::new())),
+    sleep_enabled: Mutex::new(Cell::new(false))
+});
+
+impl RtcDriver {
+    fn init(&'static self, clocks: &Clocks) {
+        let sys = unsafe { Sys::steal() };
+
+        self.clk32k
+            .store(clocks.clk32k().to_Hz(), Ordering::Relaxed);
+
+        // Alarms have to wake up from sleep
+        with_safe_mode(|| {
+            sys.slp_wake_ctrl()
+                .modify(|_, w| w.slp_rtc_wake().set_bit().wake_ev_mode().set_bit());
+        });
+    }
+
+    fn cnt(&self) -> u64 {
+        rtc::cnt_total(&unsafe { Sys::steal() })
+    }
+
+    fn trigger_alarm(&self, cs: CriticalSection) {
+        let mut next = self
+            .queue
+            .borrow(cs)
+            .borrow_mut()
+            .next_expiration(self.cnt());
+        while !self.set_alarm(cs, next) {
+            next = self
+                .queue
+                .borrow(cs)
+                .borrow_mut()
+                .next_expiration(self.cnt());
+        }
+    }
+
+    fn set_alarm(&self, cs: CriticalSection, next_alarm_cnt: u64) -> bool {
+        self.alarm_cnt.borrow(cs).set(next_alarm_cnt);
+
+        // Already passed
+        if next_alarm_cnt <= self.cnt() {
+            rtc::clear_trigger(cs, Trigger::TimeDriver);
+            return false;
+        }
+
+        rtc::set_trigger(cs, Trigger::TimeDriver, next_alarm_cnt);
+        true
+    }
+
+    fn idle(&self, cs: CriticalSection) {
+        // A plain wfi would halt if SLEEPDEEP was left set
+        let sys = unsafe { Sys::steal() };
+        if !self.sleep_enabled.borrow(cs).get() {
+            sys.idle();
+            return;
+        }
+
+        // Without an alarm nothing might wake up in time, and peripherals being
+        // waited on stop along with the HSE
+        let alarm_cnt = self.alarm_cnt.borrow(cs).get();
+        if alarm_cnt == u64::MAX || !power::may_sleep() {
+            sys.idle();
+            return;
+        }
+
+        // Not worth the wake-up latency, the RTC keeps counting either way
+        if alarm_cnt.saturating_sub(self.cnt()) < MIN_SLEEP_CNT {
+            sys.idle();
+            return;
+        }
+
+        sys.sleep(Retention::all());
+    }
+}
+
+impl Driver for RtcDriver {
+    fn now(&self) -> u64 {
+        let clk32k = self.clk32k.load(Ordering::Relaxed) as u128;
+        (self.cnt() as u128 * TICK_HZ as u128 / clk32k) as u64
+    }
+
+    fn schedule_wake(&self, at: u64, waker: &core::task::Waker) {
+        let clk32k = self.clk32k.load(Ordering::Relaxed) as u128;
+        // Round up to not wake up before `at`
+        let at_cnt = (at as u128 * clk32k)
+            .div_ceil(TICK_HZ as u128)
+            .min(u64::MAX as u128) as u64;
+        critical_section::with(|cs| {
+            let mut queue = self.queue.borrow(cs).borrow_mut();
+            if queue.schedule_wake(at_cnt, waker) {
+                let mut next = queue.next_expiration(self.cnt());
+                while !self.set_alarm(cs, next) {
+                    next = queue.next_expiration(self.cnt());
+                }
+            }
+        })
+    }
+}
+
+pub fn init(clocks: &Clocks, pfic: &Pfic) {
+    DRIVER.init(clocks);
+
+    pfic.set_priority(ExternalInterrupt::RTC, Priority::P15);
+    pfic.enable(ExternalInterrupt::RTC);
+}
+
+/// Lets the executor enter sleep between alarms, the frozen clocks are
+/// restored on every wake-up.
+pub fn enable_sleep() {
+    critical_section::with(|cs| DRIVER.sleep_enabled.borrow(cs).set(true));
+}
+
+pub fn disable_sleep() {
+    critical_section::with(|cs| DRIVER.sleep_enabled.borrow(cs).set(false));
+}
+
+pub(crate) fn idle(cs: CriticalSection) {
+    DRIVER.idle(cs);
+}
+
+/// Called from the RTC interrupt once the trigger requested by this driver
+/// has passed.
+pub(crate) fn trigger_alarm(cs: CriticalSection) {
+    DRIVER.trigger_alarm(cs);
+}
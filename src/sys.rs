@@ -1,163 +1,357 @@
+use core::cell::Cell;
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use fugit::HertzU32;
 use riscv::asm::{delay, nop};
 
+const HSE_HZ: u32 = 32_000_000;
+const PLL_HZ: u32 = 480_000_000;
+
+/// Config the clocks were frozen with, the only one restored after sleep
+static FROZEN: Mutex<CriticalSectionRawMutex, Cell<Option<Config>>> = Mutex::new(Cell::new(None));
+
 pub trait SysExt {
-    fn set(self, config: Config) -> Self;
-    fn fsys(&self) -> u32;
+    /// Sets up the clock tree once, drivers rely on the returned [`Clocks`]
+    /// staying valid from then on.
+    fn freeze(&self, config: Config) -> Clocks;
+    fn calibrate_lsi(&self, clocks: &Clocks);
 }
 
 impl SysExt for pac::Sys {
-    fn set(self, config: Config) -> Self {
-        match config.clock32ksrc {
-            Clock32KSrc::LSE => {
-                with_safe_mode(|| {
-                    self.ck32k_config()
-                        .modify(|_, w| w.clk_xt32k_pon().set_bit());
-                });
-                delay(self.fsys() / 10 / 4);
-                with_safe_mode(|| {
-                    self.ck32k_config()
-                        .modify(|_, w| w.clk_osc32k_xt().set_bit());
-                });
-                delay(self.fsys() / 1000);
-            }
-            Clock32KSrc::LSI => {
-                with_safe_mode(|| {
-                    self.ck32k_config()
-                        .modify(|_, w| w.clk_osc32k_xt().clear_bit().clk_int32k_pon().set_bit());
-                });
-            }
-        }
+    fn freeze(&self, config: Config) -> Clocks {
+        critical_section::with(|cs| {
+            let frozen = FROZEN.borrow(cs);
+            assert!(frozen.get().is_none(), "clocks are already frozen");
+            frozen.set(Some(config));
+        });
+        set(self, config);
+
+        config.clocks()
+    }
+
+    fn calibrate_lsi(&self, clocks: &Clocks) {
+        // The reference is fsys, so it has to be derived from HSE or PLL
+        assert!(self.clk_sys_cfg().read().clk_sys_mod().bits() != 0b11);
+
+        // Count fsys cycles over 2 LSI cycles, a deviation of one tune step is
+        // roughly 37 cycles at 32 MHz
+        let fsys_khz = clocks.fsys().to_kHz() as i32;
+        let expected = 2000 * fsys_khz / 32_000;
+        let step = 37 * fsys_khz / 32_000;
 
         with_safe_mode(|| {
-            self.pll_config()
-                .modify(|r, w| unsafe { w.pll_cfg_dat().bits(r.pll_cfg_dat().bits() & !(1 << 5)) });
+            self.osc_cal_ctrl()
+                .modify(|_, w| unsafe { w.osc_cnt_total().bits(1).osc_cnt_en().set_bit() });
         });
-        match config.clocksyssrc {
-            ClockSysSrc::Clock32K => {
-                with_safe_mode(|| {
-                    self.clk_sys_cfg()
-                        .modify(|_, w| unsafe { w.clk_sys_mod().bits(0b11) });
-                });
-            }
-            ClockSysSrc::HSE(div) => {
-                if self.hfck_pwr_ctrl().read().clk_xt32m_pon().bit_is_clear() {
-                    with_safe_mode(|| {
-                        self.hfck_pwr_ctrl()
-                            .modify(|_, w| w.clk_xt32m_pon().set_bit());
-                    });
-                    delay(2400);
-                }
+        unsafe {
+            self.osc_cal_cnt()
+                .as_ptr()
+                .write_volatile(OSC_CAL_OV_CLR | OSC_CAL_IF)
+        };
 
-                with_safe_mode(|| {
-                    self.clk_sys_cfg().write(|w| unsafe {
-                        w.clk_sys_mod().bits(0b00).clk_pll_div().bits(div & 0x1F)
-                    });
-                    nop();
-                    nop();
-                    nop();
-                    nop();
-                });
+        for _ in 0..3 {
+            // Discard the first sample, it was taken with the old tune value
+            while self.osc_cal_ctrl().read().osc_cnt_halt().bit_is_clear() {}
+            while self.osc_cal_ctrl().read().osc_cnt_halt().bit_is_set() {}
+            unsafe { self.osc_cal_cnt().as_ptr().write_volatile(OSC_CAL_IF) };
+            while self.osc_cal_ctrl().read().osc_cnt_halt().bit_is_clear() {}
 
-                with_safe_mode(|| {
-                    nop();
-                    nop();
-                    self.flash_cfg().write(|w| unsafe { w.bits(0x51) });
-                });
+            let cnt = self.osc_cal_cnt().read().osc_cal_cnt().bits() as i32
+                + self.osc_cal_ov_cnt().read().bits() as i32 * 0x3FFF;
+            let offset = cnt - expected;
+            if offset.abs() < step {
+                break;
             }
-            ClockSysSrc::PLL(div) => {
-                if self.hfck_pwr_ctrl().read().clk_pll_pon().bit_is_clear() {
-                    with_safe_mode(|| {
-                        self.hfck_pwr_ctrl()
-                            .modify(|_, w| w.clk_pll_pon().set_bit());
-                    });
-                    delay(4000);
-                }
 
-                with_safe_mode(|| {
-                    self.clk_sys_cfg().write(|w| unsafe {
-                        w.clk_sys_mod().bits(0b01).clk_pll_div().bits(div & 0x1F)
-                    });
-                    nop();
-                    nop();
-                    nop();
-                    nop();
+            // Round to the nearest tune step
+            let adjust = (offset * 2 / step + offset.signum()) / 2;
+            with_safe_mode(|| {
+                self.int32k_tune().modify(|r, w| unsafe {
+                    w.int32k_tune()
+                        .bits(r.int32k_tune().bits().wrapping_add_signed(adjust as i16))
                 });
-
-                if div == 6 {
-                    with_safe_mode(|| {
-                        self.flash_cfg().write(|w| unsafe { w.bits(0x02) });
-                    });
-                } else {
-                    with_safe_mode(|| {
-                        self.flash_cfg().write(|w| unsafe { w.bits(0x52) });
-                    });
-                }
-            }
+            });
         }
+
         with_safe_mode(|| {
-            self.pll_config().modify(|_, w| w.flash_io_mod().set_bit());
+            self.osc_cal_ctrl()
+                .modify(|_, w| w.osc_cnt_en().clear_bit());
         });
+    }
+}
 
-        self
+#[derive(Clone, Copy, Debug)]
+pub struct Clocks {
+    fsys: HertzU32,
+    hclk: HertzU32,
+    clk32k: HertzU32,
+}
+
+impl Clocks {
+    pub fn fsys(&self) -> HertzU32 {
+        self.fsys
     }
 
-    fn fsys(&self) -> u32 {
-        let clk_sys_cfg = self.clk_sys_cfg().read();
-        match clk_sys_cfg.clk_sys_mod().bits() {
-            0b00 => 32_000_000 / clk_sys_cfg.clk_pll_div().bits() as u32,
-            0b01 => 480_000_000 / clk_sys_cfg.clk_pll_div().bits() as u32,
-            0b10 => 32_000_000,
-            _ => 32_000,
-        }
+    pub fn hclk(&self) -> HertzU32 {
+        self.hclk
+    }
+
+    pub fn clk32k(&self) -> HertzU32 {
+        self.clk32k
     }
 }
 
-enum Clock32KSrc {
+const OSC_CAL_OV_CLR: u16 = 1 << 14;
+const OSC_CAL_IF: u16 = 1 << 15;
+
+#[derive(Clone, Copy)]
+pub enum Clock32KSrc {
     LSE,
     LSI,
 }
 
+#[derive(Clone, Copy)]
+pub enum Xt32kCurrent {
+    Percent75,
+    Percent100,
+    Percent150,
+    Percent200,
+}
+
+#[derive(Clone, Copy)]
 enum ClockSysSrc {
     Clock32K,
     HSE(u8),
     PLL(u8),
 }
 
+#[derive(Clone, Copy)]
 pub struct Config {
     clock32ksrc: Clock32KSrc,
     clocksyssrc: ClockSysSrc,
+    xt32k_tune: Option<(Xt32kCurrent, u8)>,
+    int32k_tune: Option<u16>,
 }
 
 impl Default for Config {
     fn default() -> Self {
-        Self {
-            clock32ksrc: Clock32KSrc::LSI,
-            clocksyssrc: ClockSysSrc::HSE(5),
-        }
+        Self::hse_hz(HertzU32::kHz(6_400))
     }
 }
 
 impl Config {
-    pub fn clock32k() -> Self {
+    pub const fn clock32k() -> Self {
         Self {
             clock32ksrc: Clock32KSrc::LSI,
             clocksyssrc: ClockSysSrc::Clock32K,
+            xt32k_tune: None,
+            int32k_tune: None,
         }
     }
 
-    pub fn hse(div: u8) -> Self {
+    /// Divides the 32 MHz HSE down to `fsys`, panics (at compile time in a
+    /// const context) if it is not an exact divider in 2..=31.
+    pub const fn hse_hz(fsys: HertzU32) -> Self {
+        let div = HSE_HZ / fsys.to_Hz();
+        assert!(
+            div >= 2 && div <= 31 && HSE_HZ.is_multiple_of(fsys.to_Hz()),
+            "fsys must be 32 MHz divided by 2..=31"
+        );
+
         Self {
             clock32ksrc: Clock32KSrc::LSI,
-            clocksyssrc: ClockSysSrc::HSE(div),
+            clocksyssrc: ClockSysSrc::HSE(div as u8),
+            xt32k_tune: None,
+            int32k_tune: None,
         }
     }
 
-    pub fn pll(div: u8) -> Self {
+    /// Divides the 480 MHz PLL down to `fsys`, panics (at compile time in a
+    /// const context) if it is not an exact divider in 6..=31, which also
+    /// caps fsys at 80 MHz.
+    pub const fn pll_hz(fsys: HertzU32) -> Self {
+        let div = PLL_HZ / fsys.to_Hz();
+        assert!(
+            div >= 6 && div <= 31 && PLL_HZ.is_multiple_of(fsys.to_Hz()),
+            "fsys must be 480 MHz divided by 6..=31"
+        );
+
         Self {
             clock32ksrc: Clock32KSrc::LSI,
-            clocksyssrc: ClockSysSrc::PLL(div),
+            clocksyssrc: ClockSysSrc::PLL(div as u8),
+            xt32k_tune: None,
+            int32k_tune: None,
         }
     }
+
+    pub const fn clock32k_src(mut self, clock32ksrc: Clock32KSrc) -> Self {
+        self.clock32ksrc = clock32ksrc;
+        self
+    }
+
+    /// Trims the LSE drive current and load capacitance, `c_load` is in pF
+    /// and must be in 12..=27.
+    pub const fn xt32k_tune(mut self, current: Xt32kCurrent, c_load: u8) -> Self {
+        assert!(c_load >= 12 && c_load <= 27, "c_load must be in 12..=27 pF");
+        self.xt32k_tune = Some((current, c_load - 12));
+        self
+    }
+
+    /// Overrides the factory LSI trim, see [`SysExt::calibrate_lsi`] for
+    /// deriving it at runtime.
+    pub const fn int32k_tune(mut self, tune: u16) -> Self {
+        assert!(tune < 0x2000, "tune must fit in 13 bits");
+        self.int32k_tune = Some(tune);
+        self
+    }
+
+    /// Same 32 kHz setup, but fsys from HSE/5 which is what the flash
+    /// timing has to be set for when entering sleep.
+    fn for_sleep(mut self) -> Self {
+        self.clocksyssrc = ClockSysSrc::HSE(5);
+        self
+    }
+
+    fn clocks(&self) -> Clocks {
+        let clk32k = match self.clock32ksrc {
+            Clock32KSrc::LSE => HertzU32::Hz(32_768),
+            Clock32KSrc::LSI => HertzU32::Hz(32_000),
+        };
+        let fsys = match self.clocksyssrc {
+            ClockSysSrc::Clock32K => clk32k,
+            ClockSysSrc::HSE(div) => HertzU32::Hz(HSE_HZ / div as u32),
+            ClockSysSrc::PLL(div) => HertzU32::Hz(PLL_HZ / div as u32),
+        };
+
+        Clocks {
+            fsys,
+            hclk: fsys,
+            clk32k,
+        }
+    }
+}
+
+/// Switches fsys to HSE/5 for entering sleep, see [`restore_clocks`].
+pub(crate) fn set_sleep_clocks(sys: &pac::Sys) {
+    set(sys, frozen_config().for_sleep());
+}
+
+/// Brings back the frozen clock tree after waking up.
+pub(crate) fn restore_clocks(sys: &pac::Sys) {
+    set(sys, frozen_config());
+}
+
+fn frozen_config() -> Config {
+    critical_section::with(|cs| FROZEN.borrow(cs).get()).expect("clocks are not frozen")
+}
+
+fn set(sys: &pac::Sys, config: Config) {
+    if let Some((current, c_load)) = config.xt32k_tune {
+        with_safe_mode(|| {
+            sys.xt32k_tune().modify(|_, w| unsafe {
+                w.xt32k_i_tune()
+                    .bits(current as u8)
+                    .xt32k_c_load()
+                    .bits(c_load)
+            });
+        });
+    }
+    if let Some(tune) = config.int32k_tune {
+        with_safe_mode(|| {
+            sys.int32k_tune()
+                .write(|w| unsafe { w.int32k_tune().bits(tune) });
+        });
+    }
+
+    match config.clock32ksrc {
+        // Skip the start-up delay when the LSE is already running, e.g.
+        // when restoring the config after sleep
+        Clock32KSrc::LSE if sys.ck32k_config().read().clk_osc32k_xt().bit_is_set() => {}
+        Clock32KSrc::LSE => {
+            with_safe_mode(|| {
+                sys.ck32k_config()
+                    .modify(|_, w| w.clk_xt32k_pon().set_bit());
+            });
+            delay(fsys(sys).to_Hz() / 10 / 4);
+            with_safe_mode(|| {
+                sys.ck32k_config()
+                    .modify(|_, w| w.clk_osc32k_xt().set_bit());
+            });
+            delay(fsys(sys).to_Hz() / 1000);
+        }
+        Clock32KSrc::LSI => {
+            with_safe_mode(|| {
+                sys.ck32k_config()
+                    .modify(|_, w| w.clk_osc32k_xt().clear_bit().clk_int32k_pon().set_bit());
+            });
+        }
+    }
+
+    with_safe_mode(|| {
+        sys.pll_config()
+            .modify(|r, w| unsafe { w.pll_cfg_dat().bits(r.pll_cfg_dat().bits() & !(1 << 5)) });
+    });
+    match config.clocksyssrc {
+        ClockSysSrc::Clock32K => {
+            with_safe_mode(|| {
+                sys.clk_sys_cfg()
+                    .modify(|_, w| unsafe { w.clk_sys_mod().bits(0b11) });
+            });
+        }
+        ClockSysSrc::HSE(div) => {
+            if sys.hfck_pwr_ctrl().read().clk_xt32m_pon().bit_is_clear() {
+                with_safe_mode(|| {
+                    sys.hfck_pwr_ctrl()
+                        .modify(|_, w| w.clk_xt32m_pon().set_bit());
+                });
+                delay(2400);
+            }
+
+            with_safe_mode(|| {
+                sys.clk_sys_cfg()
+                    .write(|w| unsafe { w.clk_sys_mod().bits(0b00).clk_pll_div().bits(div) });
+                nop();
+                nop();
+                nop();
+                nop();
+            });
+
+            with_safe_mode(|| {
+                nop();
+                nop();
+                sys.flash_cfg().write(|w| unsafe { w.bits(0x51) });
+            });
+        }
+        ClockSysSrc::PLL(div) => {
+            if sys.hfck_pwr_ctrl().read().clk_pll_pon().bit_is_clear() {
+                with_safe_mode(|| {
+                    sys.hfck_pwr_ctrl().modify(|_, w| w.clk_pll_pon().set_bit());
+                });
+                delay(4000);
+            }
+
+            with_safe_mode(|| {
+                sys.clk_sys_cfg()
+                    .write(|w| unsafe { w.clk_sys_mod().bits(0b01).clk_pll_div().bits(div) });
+                nop();
+                nop();
+                nop();
+                nop();
+            });
+
+            if div == 6 {
+                with_safe_mode(|| {
+                    sys.flash_cfg().write(|w| unsafe { w.bits(0x02) });
+                });
+            } else {
+                with_safe_mode(|| {
+                    sys.flash_cfg().write(|w| unsafe { w.bits(0x52) });
+                });
+            }
+        }
+    }
+    with_safe_mode(|| {
+        sys.pll_config().modify(|_, w| w.flash_io_mod().set_bit());
+    });
 }
 
 pub fn with_safe_mode<R>(f: impl FnOnce() -> R) -> R {
@@ -180,3 +374,13 @@ pub fn with_safe_mode<R>(f: impl FnOnce() -> R) -> R {
         value
     })
 }
+
+fn fsys(sys: &pac::Sys) -> HertzU32 {
+    let clk_sys_cfg = sys.clk_sys_cfg().read();
+    HertzU32::Hz(match clk_sys_cfg.clk_sys_mod().bits() {
+        0b00 => HSE_HZ / clk_sys_cfg.clk_pll_div().bits() as u32,
+        0b01 => PLL_HZ / clk_sys_cfg.clk_pll_div().bits() as u32,
+        0b10 => HSE_HZ,
+        _ => 32_000,
+    })
+}
@@ -1,6 +1,11 @@
-use crate::{pfic::PficExt, sys::SysExt};
+use crate::{
+    pfic::PficExt,
+    power::{self, PowerExt, Retention},
+    rtc::{self, Trigger},
+    sys::{Clocks, with_safe_mode},
+};
 use core::{
-    cell::{OnceCell, RefCell},
+    cell::{Cell, OnceCell, RefCell},
     sync::atomic::{AtomicU32, Ordering},
 };
 use critical_section::CriticalSection;
@@ -9,13 +14,19 @@ use embassy_time_driver::{Driver, TICK_HZ};
 use embassy_time_queue_utils::Queue;
 use pac::{
     Pfic, Sys, Systick,
-    interrupt::{CoreInterrupt, Priority},
+    interrupt::{CoreInterrupt, ExternalInterrupt, Priority},
 };
 
+/// Shortest time worth entering sleep for, in 32 kHz cycles
+const MIN_SLEEP_CNT: u64 = 96;
+/// Time to wake up early for the HSE and PLL to settle, in 32 kHz cycles
+const WAKE_MARGIN_CNT: u64 = 48;
+
 pub struct SystickDriver {
     systick: OnceCell<Systick>,
     cnt_per_tick: AtomicU32,
     queue: Mutex<CriticalSectionRawMutex, RefCell<Queue>>,
+    sleep_clocks: Mutex<CriticalSectionRawMutex, Cell<Option<Clocks>>>,
 }
 
 unsafe impl Sync for SystickDriver {}
@@ -23,15 +34,16 @@ unsafe impl Sync for SystickDriver {}
 embassy_time_driver::time_driver_impl!(static DRIVER: SystickDriver = SystickDriver {
     systick: OnceCell::new(),
     cnt_per_tick: AtomicU32::new(0),
-    queue: Mutex::new(RefCell::new(Queue::new()))
+    queue: Mutex::new(RefCell::new(Queue::new())),
+    sleep_clocks: Mutex::new(Cell::new(None))
 });
 
 impl SystickDriver {
-    fn init(&'static self, systick: Systick, sys: &Sys) {
+    fn init(&'static self, systick: Systick, clocks: &Clocks) {
         self.systick.set(systick).unwrap();
         let systick = self.systick.get().unwrap();
 
-        let cnt_per_second = sys.fsys() as u64;
+        let cnt_per_second = clocks.hclk().to_Hz() as u64;
         let cnt_per_tick = cnt_per_second / TICK_HZ;
         self.cnt_per_tick
             .store(cnt_per_tick as u32, Ordering::Relaxed);
@@ -101,6 +113,69 @@ impl SystickDriver {
 
         true
     }
+
+    fn idle(&self, cs: CriticalSection) {
+        // A plain wfi would halt if SLEEPDEEP was left set
+        let sys = unsafe { Sys::steal() };
+        let Some(clocks) = self.sleep_clocks.borrow(cs).get() else {
+            sys.idle();
+            return;
+        };
+
+        let systick = self.systick.get().unwrap();
+        let pfic = unsafe { Pfic::steal() };
+        let hclk = clocks.hclk().to_Hz() as u64;
+        let clk32k = clocks.clk32k().to_Hz() as u64;
+
+        let cnt = self.cnt();
+        let rtc_start = rtc::cnt_total(&sys);
+
+        // SysTick stops during sleep, so the RTC has to take over the alarm
+        let alarm_cnt = if systick.ctl().read().stie().bit_is_set() {
+            systick.cmp().read().bits()
+        } else {
+            u64::MAX
+        };
+        // Without an alarm nothing might wake up in time, and peripherals being
+        // waited on stop along with the HSE
+        if alarm_cnt == u64::MAX || !power::may_sleep() {
+            sys.idle();
+            return;
+        }
+
+        let remaining = (alarm_cnt.saturating_sub(cnt).saturating_mul(clk32k) / hclk)
+            .min(rtc::MAX_CNT as u64 / 2);
+
+        // Not worth the wake-up latency
+        if remaining < MIN_SLEEP_CNT {
+            sys.idle();
+            return;
+        }
+
+        rtc::set_trigger(
+            cs,
+            Trigger::TimeDriver,
+            rtc_start + remaining - WAKE_MARGIN_CNT,
+        );
+
+        pfic.enable(ExternalInterrupt::RTC);
+        sys.sleep(Retention::all());
+
+        // Whatever woke us up, SysTick takes over again
+        rtc::clear_trigger(cs, Trigger::TimeDriver);
+
+        // Advance SysTick by the time spent sleeping
+        let elapsed = rtc::cnt_total(&sys) - rtc_start;
+        let resynced = cnt + elapsed * hclk / clk32k;
+        systick
+            .cnt()
+            .write(|w| unsafe { w.cnt().bits(resynced.max(self.cnt())) });
+
+        // The compare value was skipped over and won't match anymore
+        if alarm_cnt <= self.cnt() {
+            pfic.pend(CoreInterrupt::SysTick);
+        }
+    }
 }
 
 impl Driver for SystickDriver {
@@ -123,13 +198,32 @@ impl Driver for SystickDriver {
     }
 }
 
-pub fn init(systick: Systick, sys: &Sys, pfic: &Pfic) {
-    DRIVER.init(systick, sys);
+pub fn init(systick: Systick, clocks: &Clocks, pfic: &Pfic) {
+    DRIVER.init(systick, clocks);
 
     pfic.set_priority(CoreInterrupt::SysTick, Priority::P15);
     pfic.enable(CoreInterrupt::SysTick);
 }
 
+/// Lets the executor enter sleep between alarms, the frozen `clocks` are
+/// restored on every wake-up.
+pub fn enable_sleep(clocks: &Clocks) {
+    with_safe_mode(|| {
+        unsafe { Sys::steal() }
+            .slp_wake_ctrl()
+            .modify(|_, w| w.slp_rtc_wake().set_bit().wake_ev_mode().set_bit());
+    });
+    critical_section::with(|cs| DRIVER.sleep_clocks.borrow(cs).set(Some(*clocks)));
+}
+
+pub fn disable_sleep() {
+    critical_section::with(|cs| DRIVER.sleep_clocks.borrow(cs).set(None));
+}
+
+pub(crate) fn idle(cs: CriticalSection) {
+    DRIVER.idle(cs);
+}
+
 #[riscv_rt::core_interrupt(CoreInterrupt::SysTick)]
 fn systick() {
     critical_section::with(|cs| DRIVER.trigger_alarm(cs));
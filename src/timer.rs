@@ -0,0 +1,537 @@
+use crate::{
+    pfic::PficExt,
+    power::StayAwake,
+    remap::{self, Pins, Remap},
+    sys::Clocks,
+};
+use core::{
+    convert::Infallible,
+    future::poll_fn,
+    marker::PhantomData,
+    sync::atomic::{AtomicBool, Ordering},
+    task::Poll,
+};
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, waitqueue::AtomicWaker,
+};
+use fugit::{HertzU32, MicrosDurationU32};
+use hal::pwm::{ErrorType, SetDutyCycle};
+use pac::{
+    Pfic, Tmr0, Tmr1, Tmr2, Tmr3,
+    interrupt::{ExternalInterrupt, Priority},
+    tmr0::RegisterBlock,
+};
+
+/// Largest value of the 26-bit end count
+const MAX_CNT_END: u32 = 0x03FF_FFFF;
+
+const INT_CYC_END: u8 = 1 << 0;
+const INT_DATA_ACT: u8 = 1 << 1;
+const INT_FIFO_HF: u8 = 1 << 2;
+const INT_DMA_END: u8 = 1 << 3;
+const INT_FIFO_OV: u8 = 1 << 4;
+
+/// Level of a captured pulse, below it is its width in cycles
+const CAPTURE_HIGH: u32 = 1 << 25;
+
+static CYC_END_FIRED: [AtomicBool; 4] = [const { AtomicBool::new(false) }; 4];
+static DMA_END_FIRED: [AtomicBool; 4] = [const { AtomicBool::new(false) }; 4];
+static WAKERS: [AtomicWaker; 4] = [const { AtomicWaker::new() }; 4];
+static CAPTURES: [Channel<CriticalSectionRawMutex, u32, 16>; 4] = [const { Channel::new() }; 4];
+
+pub trait Instance {
+    const INDEX: usize;
+    const INTERRUPT: ExternalInterrupt;
+
+    /// TMR1 and TMR2 add DMA registers in the gaps, the others are laid out
+    /// the same on all timers
+    fn regs() -> &'static RegisterBlock;
+}
+
+macro_rules! instance {
+    ($($TMR:ident: $index:literal, $INTERRUPT:ident;)+) => {
+        $(
+            impl Instance for $TMR {
+                const INDEX: usize = $index;
+                const INTERRUPT: ExternalInterrupt = ExternalInterrupt::$INTERRUPT;
+
+                fn regs() -> &'static RegisterBlock {
+                    unsafe { &*($TMR::ptr() as *const RegisterBlock) }
+                }
+            }
+        )+
+    };
+}
+
+/// TMR1 and TMR2, which can move the FIFO contents by DMA
+pub trait DmaInstance: Instance {
+    fn dma_regs() -> &'static pac::tmr1::RegisterBlock;
+}
+
+impl DmaInstance for Tmr1 {
+    fn dma_regs() -> &'static pac::tmr1::RegisterBlock {
+        unsafe { &*Tmr1::ptr() }
+    }
+}
+
+impl DmaInstance for Tmr2 {
+    fn dma_regs() -> &'static pac::tmr1::RegisterBlock {
+        unsafe { &*(Tmr2::ptr() as *const pac::tmr1::RegisterBlock) }
+    }
+}
+
+instance! {
+    Tmr0: 0, TMR0;
+    Tmr1: 1, TMR1;
+    Tmr2: 2, TMR2;
+    Tmr3: 3, TMR3;
+}
+
+/// The duration is shorter than a cycle or longer than the 26-bit counter
+/// at the current Fsys, 1.1 s at 60 MHz.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PeriodOutOfRange;
+
+/// Counts Fsys cycles up to the end count and restarts.
+pub struct Timer<T: Instance> {
+    tmr: T,
+    fsys: u32,
+}
+
+impl<T: Instance> Timer<T> {
+    pub fn new(tmr: T, clocks: &Clocks, pfic: &Pfic) -> Self {
+        pfic.set_priority(T::INTERRUPT, Priority::P15);
+        pfic.enable(T::INTERRUPT);
+
+        Self {
+            tmr,
+            fsys: clocks.fsys().to_Hz(),
+        }
+    }
+
+    pub fn free(mut self) -> T {
+        self.stop();
+        self.tmr
+    }
+
+    /// Restarts counting, with [`Timer::wait`] completing after each
+    /// `period`.
+    pub fn start_periodic(&mut self, period: MicrosDurationU32) -> Result<(), PeriodOutOfRange> {
+        let cycles = period.to_micros() as u64 * self.fsys as u64 / 1_000_000;
+        if !(1..=MAX_CNT_END as u64).contains(&cycles) {
+            return Err(PeriodOutOfRange);
+        }
+
+        self.start(cycles as u32, true);
+        Ok(())
+    }
+
+    pub fn stop(&mut self) {
+        let regs = T::regs();
+        regs.inter_en().write(|w| unsafe { w.bits(0) });
+        regs.ctrl_mod().write(|w| w.ll_clear().set_bit());
+    }
+
+    /// Waits for the end of the current period.
+    pub async fn wait(&mut self) {
+        let _awake = StayAwake::new();
+        poll_fn(|cx| {
+            WAKERS[T::INDEX].register(cx.waker());
+            if CYC_END_FIRED[T::INDEX].swap(false, Ordering::SeqCst) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    fn start(&mut self, cycles: u32, interrupt: bool) {
+        let regs = T::regs();
+        regs.ctrl_mod().write(|w| w.ll_clear().set_bit());
+        regs.cnt_end()
+            .write(|w| unsafe { w.cnt_end().bits(cycles) });
+        regs.int_flag().write(|w| unsafe { w.bits(0xFF) });
+        CYC_END_FIRED[T::INDEX].store(false, Ordering::SeqCst);
+        regs.inter_en().write(|w| w.e_cyc_end().bit(interrupt));
+        regs.ctrl_mod().write(|w| w.ount_en().set_bit());
+    }
+
+    fn cycles(&self, ns: u32) -> u64 {
+        (ns as u64 * self.fsys as u64).div_ceil(1_000_000_000)
+    }
+}
+
+impl<T: Instance> hal::delay::DelayNs for Timer<T> {
+    fn delay_ns(&mut self, ns: u32) {
+        let regs = T::regs();
+        let mut cycles = self.cycles(ns);
+        while cycles > 0 {
+            let chunk = cycles.min(MAX_CNT_END as u64) as u32;
+            self.start(chunk, false);
+            while regs.int_flag().read().f_cyc_end().bit_is_clear() {}
+            cycles -= chunk as u64;
+        }
+        self.stop();
+    }
+}
+
+impl<T: Instance> embedded_hal_async::delay::DelayNs for Timer<T> {
+    async fn delay_ns(&mut self, ns: u32) {
+        let mut cycles = self.cycles(ns);
+        while cycles > 0 {
+            let chunk = cycles.min(MAX_CNT_END as u64) as u32;
+            self.start(chunk, true);
+            self.wait().await;
+            cycles -= chunk as u64;
+        }
+        self.stop();
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Polarity {
+    /// Low when idle, high during the duty cycle
+    ActiveHigh,
+    ActiveLow,
+}
+
+/// Periods each width is output for before the next one is taken from the
+/// FIFO
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Repeat {
+    X1 = 0,
+    X4 = 1,
+    X8 = 2,
+    X16 = 3,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PwmConfig {
+    /// Maximum duty cycle
+    pub resolution: u16,
+    pub polarity: Polarity,
+    pub repeat: Repeat,
+}
+
+impl Default for PwmConfig {
+    fn default() -> Self {
+        Self {
+            resolution: u16::MAX,
+            polarity: Polarity::ActiveHigh,
+            repeat: Repeat::X1,
+        }
+    }
+}
+
+/// The frequency is zero, above Fsys or its period longer than the 26-bit
+/// counter, gives back the timer and pin.
+pub struct FrequencyOutOfRange<T, PIN> {
+    pub tmr: T,
+    pub pin: PIN,
+}
+
+impl<T, PIN> core::fmt::Debug for FrequencyOutOfRange<T, PIN> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("FrequencyOutOfRange")
+    }
+}
+
+/// PWM on the timer's output pin, which has to be a push-pull output. The
+/// duty cycle goes from 0 to the configured resolution.
+pub struct TimerPwm<T: Instance, PIN> {
+    tmr: T,
+    pin: PIN,
+    period: u32,
+    max_duty: u16,
+}
+
+impl<T: Instance + Remap, PIN: Pins<T>> TimerPwm<T, PIN> {
+    pub fn new(
+        tmr: T,
+        pin: PIN,
+        frequency: HertzU32,
+        config: PwmConfig,
+        clocks: &Clocks,
+        pfic: &Pfic,
+    ) -> Result<Self, FrequencyOutOfRange<T, PIN>> {
+        assert!(config.resolution >= 1);
+        let period = match clocks.fsys().to_Hz().checked_div(frequency.to_Hz()) {
+            Some(period @ 1..=MAX_CNT_END) => period,
+            _ => return Err(FrequencyOutOfRange { tmr, pin }),
+        };
+        remap::select::<T, _>(&pin);
+
+        // For the end of playback
+        pfic.set_priority(T::INTERRUPT, Priority::P15);
+        pfic.enable(T::INTERRUPT);
+
+        let regs = T::regs();
+        regs.ctrl_mod().write(|w| w.ll_clear().set_bit());
+        regs.cnt_end()
+            .write(|w| unsafe { w.cnt_end().bits(period) });
+        write_fifo(regs, 0);
+        regs.ctrl_mod().write(|w| unsafe {
+            w.ount_en()
+                .set_bit()
+                .ut_en()
+                .set_bit()
+                .ut_polar()
+                .bit(config.polarity == Polarity::ActiveLow)
+                .wm_repeat()
+                .bits(config.repeat as u8)
+        });
+
+        Ok(Self {
+            tmr,
+            pin,
+            period,
+            max_duty: config.resolution,
+        })
+    }
+
+    pub fn free(self) -> (T, PIN) {
+        T::regs().ctrl_mod().write(|w| w.ll_clear().set_bit());
+        (self.tmr, self.pin)
+    }
+}
+
+impl<T: Instance, PIN> TimerPwm<T, PIN> {
+    /// Length of a PWM period in Fsys cycles, the unit of [`TimerPwm::play`].
+    pub fn period(&self) -> u32 {
+        self.period
+    }
+}
+
+impl<T: DmaInstance, PIN> TimerPwm<T, PIN> {
+    /// Outputs one width per period, in cycles. They are lent to the DMA until
+    /// it took the last one, and put back once it stopped, also when
+    /// cancelled.
+    pub async fn play(&mut self, widths: &mut &'static mut [u32]) {
+        run_dma::<T>(widths, INT_DMA_END).await
+    }
+}
+
+impl<T: Instance, PIN> ErrorType for TimerPwm<T, PIN> {
+    type Error = Infallible;
+}
+
+impl<T: Instance, PIN> SetDutyCycle for TimerPwm<T, PIN> {
+    fn max_duty_cycle(&self) -> u16 {
+        self.max_duty
+    }
+
+    fn set_duty_cycle(&mut self, duty: u16) -> Result<(), Self::Error> {
+        let width = duty.min(self.max_duty) as u64 * self.period as u64 / self.max_duty as u64;
+        write_fifo(T::regs(), width as u32);
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Edge {
+    /// Measures the time between all edges
+    Any = 0b01,
+    FallToFall = 0b10,
+    RiseToRise = 0b11,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Pulse {
+    /// Level during the pulse, only meaningful with [`Edge::Any`]
+    pub high: bool,
+    pub width: MicrosDurationU32,
+}
+
+/// Times the edges on the timer's input pin, which has to be an input.
+pub struct Capture<T: Instance, PIN> {
+    tmr: T,
+    pin: PIN,
+    fsys: u32,
+}
+
+impl<T: Instance + Remap, PIN: Pins<T>> Capture<T, PIN> {
+    /// Pulses are measured up to `timeout`, longer ones are cut short.
+    pub fn new(
+        tmr: T,
+        pin: PIN,
+        edge: Edge,
+        timeout: MicrosDurationU32,
+        clocks: &Clocks,
+        pfic: &Pfic,
+    ) -> Self {
+        let fsys = clocks.fsys().to_Hz();
+        let timeout = (timeout.to_micros() as u64 * fsys as u64 / 1_000_000)
+            .clamp(1, CAPTURE_HIGH as u64 - 1);
+        remap::select::<T, _>(&pin);
+
+        pfic.set_priority(T::INTERRUPT, Priority::P15);
+        pfic.enable(T::INTERRUPT);
+
+        CAPTURES[T::INDEX].clear();
+        let regs = T::regs();
+        regs.ctrl_mod().write(|w| w.ll_clear().set_bit());
+        regs.cnt_end()
+            .write(|w| unsafe { w.cnt_end().bits(timeout as u32) });
+        regs.int_flag().write(|w| unsafe { w.bits(0xFF) });
+        regs.inter_en().write(|w| {
+            w.e_data_act()
+                .set_bit()
+                .e_fifo_hf()
+                .set_bit()
+                .e_fifo_ov()
+                .set_bit()
+        });
+        regs.ctrl_mod().write(|w| unsafe {
+            w.ode_in()
+                .set_bit()
+                .ount_en()
+                .set_bit()
+                .ap_count()
+                .clear_bit()
+                .ap_edge()
+                .bits(edge as u8)
+        });
+
+        Self { tmr, pin, fsys }
+    }
+
+    /// Pulses captured while nobody waits are kept up to a small backlog,
+    /// later ones are dropped.
+    pub async fn next(&mut self) -> Pulse {
+        let _awake = StayAwake::new();
+        let data = CAPTURES[T::INDEX].receive().await;
+        self.to_pulse(data)
+    }
+
+    /// Decodes a raw capture, as written by [`Capture::capture_into`].
+    pub fn to_pulse(&self, data: u32) -> Pulse {
+        let cycles = data & (CAPTURE_HIGH - 1);
+        Pulse {
+            high: data & CAPTURE_HIGH != 0,
+            width: MicrosDurationU32::from_ticks(
+                (cycles as u64 * 1_000_000 / self.fsys as u64) as u32,
+            ),
+        }
+    }
+
+    pub fn free(self) -> (T, PIN) {
+        let regs = T::regs();
+        regs.inter_en().write(|w| unsafe { w.bits(0) });
+        regs.ctrl_mod().write(|w| w.ll_clear().set_bit());
+        (self.tmr, self.pin)
+    }
+}
+
+impl<T: DmaInstance, PIN> Capture<T, PIN> {
+    /// Fills `buffer` with raw captures instead of [`Capture::next`]. It is
+    /// lent to the DMA until full, and put back once it stopped, also when
+    /// cancelled.
+    pub async fn capture_into(&mut self, buffer: &mut &'static mut [u32]) {
+        // The FIFO must not be drained by the interrupt meanwhile
+        run_dma::<T>(buffer, INT_DMA_END).await
+    }
+}
+
+/// Runs the DMA once over the buffer in `slot`, with only the interrupts in
+/// `inter_en` enabled meanwhile.
+async fn run_dma<T: DmaInstance>(slot: &mut &'static mut [u32], inter_en: u8) {
+    let regs = T::regs();
+    let dma = T::dma_regs();
+
+    let buffer = core::mem::take(slot);
+    let range = buffer.as_mut_ptr_range();
+    DMA_END_FIRED[T::INDEX].store(false, Ordering::SeqCst);
+    // Only the lower 16 bits of RAM addresses are taken
+    dma.dma_beg()
+        .write(|w| unsafe { w.dma_beg().bits(range.start as u16) });
+    dma.dma_end()
+        .write(|w| unsafe { w.dma_end().bits(range.end as u16) });
+    regs.int_flag().write(|w| w.f_dma_end().set_bit());
+    let _guard = DmaGuard::<T> {
+        inter_en: regs.inter_en().read().bits(),
+        slot,
+        buffer,
+        _tmr: PhantomData,
+    };
+    regs.inter_en().write(|w| unsafe { w.bits(inter_en) });
+    dma.ctrl_dma().write(|w| w.ma_enable().set_bit());
+
+    let _awake = StayAwake::new();
+    poll_fn(|cx| {
+        WAKERS[T::INDEX].register(cx.waker());
+        if DMA_END_FIRED[T::INDEX].swap(false, Ordering::SeqCst) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+/// Stops the DMA, restores the interrupts and puts the buffer back once
+/// done, also when cancelled
+struct DmaGuard<'a, T: DmaInstance> {
+    inter_en: u8,
+    slot: &'a mut &'static mut [u32],
+    buffer: &'static mut [u32],
+    _tmr: PhantomData<T>,
+}
+
+impl<T: DmaInstance> Drop for DmaGuard<'_, T> {
+    fn drop(&mut self) {
+        T::dma_regs()
+            .ctrl_dma()
+            .write(|w| w.ma_enable().clear_bit());
+        T::regs()
+            .inter_en()
+            .write(|w| unsafe { w.bits(self.inter_en) });
+        *self.slot = core::mem::take(&mut self.buffer);
+    }
+}
+
+/// Takes the PWM width, but the PAC only declares it readable.
+fn write_fifo(regs: &RegisterBlock, value: u32) {
+    unsafe { regs.fifo().as_ptr().write_volatile(value) }
+}
+
+fn on_interrupt<T: Instance>() {
+    let regs = T::regs();
+    let flags = regs.int_flag().read().bits() & regs.inter_en().read().bits();
+    regs.int_flag().write(|w| unsafe { w.bits(flags) });
+
+    if flags & INT_CYC_END != 0 {
+        CYC_END_FIRED[T::INDEX].store(true, Ordering::SeqCst);
+    }
+    if flags & INT_DMA_END != 0 {
+        DMA_END_FIRED[T::INDEX].store(true, Ordering::SeqCst);
+    }
+    if flags & (INT_DATA_ACT | INT_FIFO_HF | INT_FIFO_OV) != 0 {
+        while regs.fifo_count().read().fifo_count().bits() > 0 {
+            let _ = CAPTURES[T::INDEX].try_send(regs.fifo().read().fifo().bits());
+        }
+    }
+    if flags != 0 {
+        WAKERS[T::INDEX].wake();
+    }
+}
+
+#[riscv_rt::external_interrupt(ExternalInterrupt::TMR0)]
+fn tmr0() {
+    on_interrupt::<Tmr0>();
+}
+
+#[riscv_rt::external_interrupt(ExternalInterrupt::TMR1)]
+fn tmr1() {
+    on_interrupt::<Tmr1>();
+}
+
+#[riscv_rt::external_interrupt(ExternalInterrupt::TMR2)]
+fn tmr2() {
+    on_interrupt::<Tmr2>();
+}
+
+#[riscv_rt::external_interrupt(ExternalInterrupt::TMR3)]
+fn tmr3() {
+    on_interrupt::<Tmr3>();
+}
@@ -0,0 +1,139 @@
+use crate::adc::{Adc, Channel};
+pub use badgick_core::touch::{Event, Filter, FilterConfig};
+use embassy_time::{Duration, Timer};
+use pac::Sys;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChargeCurrent {
+    UA35,
+    UA17_5,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    /// Charge time in ADC clocks, up to 31
+    pub charge: u8,
+    /// Discharge time in ADC clocks, up to 7
+    pub discharge: u8,
+    pub current: ChargeCurrent,
+    /// Drives the shield electrode along with the pad being measured
+    pub shield: bool,
+    /// Time between scans of all pads
+    pub period: Duration,
+    pub filter: FilterConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            charge: 0x1C,
+            discharge: 0x03,
+            current: ChargeCurrent::UA35,
+            shield: false,
+            period: Duration::from_millis(20),
+            filter: FilterConfig::default(),
+        }
+    }
+}
+
+/// Touch pad on a single-ended ADC input, like a pin in analog mode.
+pub struct Pad {
+    channel: u8,
+}
+
+impl Pad {
+    pub fn new<C: Channel>(_channel: C) -> Self {
+        const { assert!(!C::DIFFERENTIAL && C::CHANNEL < 14, "not a pad input") };
+
+        Self {
+            channel: C::CHANNEL,
+        }
+    }
+}
+
+/// Scans the pads with the converter of an [`Adc`], which is lent for each
+/// measurement so it can be shared with other channels.
+pub struct TouchKey<const N: usize> {
+    pads: [Pad; N],
+    filters: [Filter; N],
+    config: Config,
+    /// Resumes the scan after a returned event
+    next: usize,
+}
+
+impl<const N: usize> TouchKey<N> {
+    pub fn new(pads: [Pad; N], config: Config) -> Self {
+        assert!(config.charge < 32 && config.discharge < 8);
+
+        Self {
+            pads,
+            filters: [Filter::new(config.filter); N],
+            config,
+            next: 0,
+        }
+    }
+
+    pub fn filter(&self, index: usize) -> &Filter {
+        &self.filters[index]
+    }
+
+    /// Raw count of pad `index`.
+    pub fn measure(&mut self, adc: &mut Adc, index: usize) -> u16 {
+        let sys = unsafe { Sys::steal() };
+        let adc = adc.regs();
+
+        adc.channel()
+            .write(|w| unsafe { w.ch_inx().bits(self.pads[index].channel) });
+        adc.cfg().write(|w| unsafe {
+            w.power_on()
+                .set_bit()
+                .buf_en()
+                .set_bit()
+                .pga_gain()
+                .bits(0b10)
+        });
+        sys.tkey_cfg().write(|w| {
+            w.tkey_pwr_on()
+                .set_bit()
+                .tkey_current()
+                .bit(self.config.current == ChargeCurrent::UA17_5)
+                .tkey_drv_en()
+                .bit(self.config.shield)
+        });
+        sys.tkey_count().write(|w| unsafe {
+            w.tkey_charg_cnt()
+                .bits(self.config.charge)
+                .tkey_disch_cnt()
+                .bits(self.config.discharge)
+        });
+
+        sys.tkey_convert().write(|w| w.tkey_start().set_bit());
+        while sys.tkey_convert().read().tkey_start().bit_is_set() {}
+        adc.data().read().data().bits()
+    }
+
+    /// Waits for the next touch or release, returned with the pad index.
+    pub async fn next(&mut self, adc: &mut Adc) -> (usize, Event) {
+        loop {
+            while self.next < N {
+                let index = self.next;
+                self.next += 1;
+
+                let count = self.measure(adc, index);
+                if let Some(event) = self.filters[index].update(count) {
+                    return (index, event);
+                }
+            }
+
+            self.next = 0;
+            Timer::after(self.config.period).await;
+        }
+    }
+}
+
+impl<const N: usize> Drop for TouchKey<N> {
+    fn drop(&mut self) {
+        let sys = unsafe { Sys::steal() };
+        sys.tkey_cfg().write(|w| w.tkey_pwr_on().clear_bit());
+    }
+}
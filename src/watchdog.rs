@@ -0,0 +1,147 @@
+use crate::{
+    pfic::PficExt,
+    sys::{Clocks, with_safe_mode},
+};
+use core::{
+    future::poll_fn,
+    sync::atomic::{AtomicBool, Ordering},
+    task::Poll,
+};
+use embassy_sync::waitqueue::AtomicWaker;
+use embassy_time::{Duration, Ticker};
+use pac::{
+    Pfic, Sys,
+    interrupt::{ExternalInterrupt, Priority},
+};
+
+/// Fsys cycles per watchdog count
+const CYCLES_PER_CNT: u64 = 131_072;
+
+static TIMEOUT_FIRED: AtomicBool = AtomicBool::new(false);
+static TIMEOUT_WAKER: AtomicWaker = AtomicWaker::new();
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimeoutOutOfRange;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Resets the chip on timeout
+    Reset,
+    /// Raises the WDOG_BAT interrupt on timeout, see [`Watchdog::wait_timeout`]
+    Interrupt,
+}
+
+/// 8-bit watchdog counting up at Fsys/131072 and timing out on overflow.
+pub struct Watchdog {
+    reload: u8,
+    mode: Mode,
+}
+
+impl Watchdog {
+    /// Fails for timeouts below one count or above [`Self::max_timeout_ms`].
+    pub fn new(
+        clocks: &Clocks,
+        timeout_ms: u32,
+        mode: Mode,
+        pfic: &Pfic,
+    ) -> Result<Self, TimeoutOutOfRange> {
+        let cnt = timeout_ms as u64 * clocks.fsys().to_Hz() as u64 / CYCLES_PER_CNT / 1_000;
+        if !(1..=256).contains(&cnt) {
+            return Err(TimeoutOutOfRange);
+        }
+
+        if mode == Mode::Interrupt {
+            pfic.set_priority(ExternalInterrupt::WDOG_BAT, Priority::P15);
+            pfic.enable(ExternalInterrupt::WDOG_BAT);
+        }
+
+        Ok(Self {
+            reload: (256 - cnt) as u8,
+            mode,
+        })
+    }
+
+    /// 256 counts, around 559 ms at 60 MHz.
+    pub fn max_timeout_ms(clocks: &Clocks) -> u32 {
+        (256 * CYCLES_PER_CNT * 1_000 / clocks.fsys().to_Hz() as u64) as u32
+    }
+
+    pub fn start(&mut self) {
+        self.feed();
+
+        let sys = unsafe { Sys::steal() };
+        with_safe_mode(|| {
+            sys.rst_wdog_ctrl().modify(|_, w| {
+                w.wdog_int_flag()
+                    .set_bit()
+                    .wdog_rst_en()
+                    .bit(self.mode == Mode::Reset)
+                    .wdog_int_en()
+                    .bit(self.mode == Mode::Interrupt)
+            });
+        });
+    }
+
+    pub fn stop(&mut self) {
+        let sys = unsafe { Sys::steal() };
+        with_safe_mode(|| {
+            sys.rst_wdog_ctrl().modify(|_, w| {
+                w.wdog_int_flag()
+                    .set_bit()
+                    .wdog_rst_en()
+                    .clear_bit()
+                    .wdog_int_en()
+                    .clear_bit()
+            });
+        });
+    }
+
+    pub fn feed(&mut self) {
+        let sys = unsafe { Sys::steal() };
+        sys.wdog_count()
+            .write(|w| unsafe { w.wdog_count().bits(self.reload) });
+    }
+
+    /// Only completes in [`Mode::Interrupt`].
+    pub async fn wait_timeout(&mut self) {
+        poll_fn(|cx| {
+            TIMEOUT_WAKER.register(cx.waker());
+            if TIMEOUT_FIRED.swap(false, Ordering::SeqCst) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+}
+
+/// Starts the watchdog and feeds it every `period`, which has to be well
+/// below the timeout.
+#[embassy_executor::task]
+pub async fn feed(mut watchdog: Watchdog, period: Duration) {
+    watchdog.start();
+
+    let mut ticker = Ticker::every(period);
+    loop {
+        ticker.next().await;
+        watchdog.feed();
+    }
+}
+
+#[riscv_rt::external_interrupt(ExternalInterrupt::WDOG_BAT)]
+fn wdog_bat() {
+    let sys = unsafe { Sys::steal() };
+    if sys.rst_wdog_ctrl().read().wdog_int_flag().bit_is_set() {
+        // The flag is cleared by writing one, other bits are kept
+        with_safe_mode(|| {
+            sys.rst_wdog_ctrl()
+                .modify(|_, w| w.wdog_int_flag().set_bit());
+        });
+
+        TIMEOUT_FIRED.store(true, Ordering::SeqCst);
+        TIMEOUT_WAKER.wake();
+    }
+
+    crate::battery::on_interrupt();
+}
@@ -0,0 +1,75 @@
+use crate::{
+    remap::{Pins, Remap},
+    sys::Clocks,
+    timer::{DmaInstance, PwmConfig, TimerPwm},
+};
+pub use badgick_core::ws2812::{BufferTooSmall, Encoder};
+use embassy_time::Timer;
+use fugit::HertzU32;
+use pac::Pfic;
+use smart_leds_trait::{RGB8, SmartLedsWriteAsync};
+
+/// Bit rate, each bit is one PWM period
+const BIT_HZ: u32 = 800_000;
+/// Low time that latches the data, newer parts need more than the 50 µs of
+/// the original
+const RESET_US: u64 = 300;
+
+/// WS2812 strip on the output of TMR1 or TMR2, fed by DMA.
+pub struct Ws2812<T: DmaInstance, PIN> {
+    pwm: TimerPwm<T, PIN>,
+    encoder: Encoder,
+    /// See [`Encoder::buffer_len`] for the size needed
+    buffer: &'static mut [u32],
+}
+
+impl<T: DmaInstance + Remap, PIN: Pins<T>> Ws2812<T, PIN> {
+    pub fn new(tmr: T, pin: PIN, buffer: &'static mut [u32], clocks: &Clocks, pfic: &Pfic) -> Self {
+        let pwm = TimerPwm::new(
+            tmr,
+            pin,
+            HertzU32::from_raw(BIT_HZ),
+            // Widths are played back in cycles instead
+            PwmConfig {
+                resolution: 1,
+                ..Default::default()
+            },
+            clocks,
+            pfic,
+        )
+        // Only when running from the 32 kHz clock, too slow for the timing
+        // anyway
+        .expect("fsys too low for WS2812");
+
+        Self {
+            encoder: Encoder::new(pwm.period()),
+            pwm,
+            buffer,
+        }
+    }
+}
+
+impl<T: DmaInstance, PIN> SmartLedsWriteAsync for Ws2812<T, PIN> {
+    type Error = BufferTooSmall;
+    type Color = RGB8;
+
+    async fn write<I, C>(&mut self, iterator: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = C>,
+        C: Into<Self::Color>,
+    {
+        let len = self.encoder.encode(
+            iterator.into_iter().map(|color| {
+                let color = color.into();
+                [color.r, color.g, color.b]
+            }),
+            self.buffer,
+        )?;
+        // Unused widths keep the line low, like the reset time after
+        self.buffer[len..].fill(0);
+
+        self.pwm.play(&mut self.buffer).await;
+        Timer::after_micros(RESET_US).await;
+        Ok(())
+    }
+}